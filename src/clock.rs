@@ -0,0 +1,80 @@
+pub mod time_source {
+    use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+    use std::fmt;
+    use std::sync::Arc;
+
+    /// Where the current time comes from, so scheduling can be simulated
+    pub trait Clock: fmt::Debug + Send + Sync {
+        fn now(&self) -> DateTime<Local>;
+
+        /// The current time as a unix timestamp, the way the Freemind registry stores it
+        fn unix(&self) -> u32 {
+            self.now().timestamp().try_into().unwrap_or(0)
+        }
+    }
+
+    pub type SharedClock = Arc<dyn Clock>;
+
+    /// The time of this machine
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> DateTime<Local> {
+            Local::now()
+        }
+    }
+
+    /// Always the same point in time
+    #[derive(Debug, Clone, Copy)]
+    pub struct FixedClock(pub DateTime<Local>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Local> {
+            self.0
+        }
+    }
+
+    pub fn system_clock() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+
+    /// Parses a point in time given either as RFC 3339 or as `YYYY-MM-DD HH:MM` in local time
+    pub fn parse_time(text: &str) -> Option<DateTime<Local>> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Some(time.with_timezone(&Local));
+        }
+        ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"]
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+            .and_then(|time| Local.from_local_datetime(&time).earliest())
+    }
+
+    /// Switches the local time zone to `tz` for a test until the guard is dropped.
+    /// Tests depending on the local time zone run one at a time through this.
+    #[cfg(test)]
+    pub fn local_time_zone(tz: &str) -> LocalTimeZone {
+        static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let lock = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let previous = std::env::var_os("TZ");
+        std::env::set_var("TZ", tz);
+        LocalTimeZone { previous, _lock: lock }
+    }
+
+    /// Puts the time zone of the process back when dropped
+    #[cfg(test)]
+    pub struct LocalTimeZone {
+        previous: Option<std::ffi::OsString>,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    #[cfg(test)]
+    impl Drop for LocalTimeZone {
+        fn drop(&mut self) {
+            match &self.previous {
+                Some(tz) => std::env::set_var("TZ", tz),
+                None => std::env::remove_var("TZ"),
+            }
+        }
+    }
+}
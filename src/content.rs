@@ -1,58 +1,901 @@
 pub mod speech {
-    use chrono::{Duration, NaiveTime, Utc};
+    use chrono::NaiveTime;
     use reqwest;
     use bytes::Bytes;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::future::Future;
+    use std::process::Stdio;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::process::Command;
+
+    #[derive(Debug)]
+    pub enum TtsError {
+        Http(reqwest::Error),
+        Process(std::io::Error),
+        Engine(String),
+    }
+
+    impl fmt::Display for TtsError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TtsError::Http(err) => write!(f, "TTS request failed: {}", err),
+                TtsError::Process(err) => write!(f, "TTS process failed: {}", err),
+                TtsError::Engine(msg) => write!(f, "TTS engine failed: {}", msg),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for TtsError {
+        fn from(err: reqwest::Error) -> Self {
+            TtsError::Http(err)
+        }
+    }
+
+    impl From<std::io::Error> for TtsError {
+        fn from(err: std::io::Error) -> Self {
+            TtsError::Process(err)
+        }
+    }
+
+    /// How announcements should sound
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct SpeechConfig {
+        /// Language code like `en-gb` or `de-de`, also selects the phrasing
+        language: String,
+        voice: String,
+        /// Audio codec as understood by VoiceRSS (`MP3`, `WAV`, `OGG`, `AAC`)
+        codec: String,
+        sample_rate: String,
+        /// Speaking rate from -10 (slowest) to 10 (fastest)
+        rate: i8,
+    }
 
+    impl ::std::default::Default for SpeechConfig {
+        fn default() -> Self {
+            Self {
+                language: "en-gb".to_string(),
+                voice: "Nancy".to_string(),
+                codec: "MP3".to_string(),
+                sample_rate: "48khz_16bit_stereo".to_string(),
+                rate: 0,
+            }
+        }
+    }
 
+    impl fmt::Display for SpeechConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
 
-    pub async fn get_speech_voicerss(text: &str, tts_api_key: &str) -> Result<Bytes, reqwest::Error> {
+    impl SpeechConfig {
+        pub fn language(&self) -> Language {
+            Language::from_code(&self.language)
+        }
+
+        /// File extension matching the configured codec
+        fn codec_extension(&self) -> String {
+            self.codec.to_lowercase()
+        }
+    }
+
+    /// The languages the announcements can be phrased in
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Language {
+        English,
+        German,
+    }
+
+    impl Language {
+        /// Picks the phrasing from a language code, defaulting to English
+        pub fn from_code(code: &str) -> Self {
+            match code.to_lowercase().split(['-', '_']).next() {
+                Some("de") => Language::German,
+                _ => Language::English,
+            }
+        }
+
+        pub fn greeting(&self, time: NaiveTime) -> &'static str {
+            let index = if time > NaiveTime::from_hms_opt(18, 30, 0).unwrap() {
+                4
+            } else if time > NaiveTime::from_hms_opt(15, 30, 0).unwrap() {
+                3
+            } else if time > NaiveTime::from_hms_opt(11, 30, 0).unwrap() {
+                2
+            } else if time > NaiveTime::from_hms_opt(5, 30, 0).unwrap() {
+                1
+            } else {
+                0
+            };
+            match self {
+                Language::English => ["Good Night", "Good Morning", "Good Day", "Good Afternoon", "Good Evening"][index],
+                Language::German => ["Gute Nacht", "Guten Morgen", "Guten Tag", "Guten Tag", "Guten Abend"][index],
+            }
+        }
+
+        pub fn weekday(&self, index: usize) -> &'static str {
+            match self {
+                Language::English => ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"][index],
+                Language::German => ["Montag", "Dienstag", "Mittwoch", "Donnerstag", "Freitag", "Samstag", "Sonntag"][index],
+            }
+        }
+
+        pub fn month(&self, index: usize) -> &'static str {
+            match self {
+                Language::English => ["January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December"][index],
+                Language::German => ["Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August", "September", "Oktober", "November", "Dezember"][index],
+            }
+        }
+
+        /// The date as it should be read out
+        pub fn spoken_date(&self, day: u32, month: usize, year: i32) -> String {
+            match self {
+                Language::English => format!("{} {} {}", day, self.month(month), year),
+                Language::German => format!("{}. {} {}", day, self.month(month), year),
+            }
+        }
+
+        /// Built-in template for the greeting with the current date and time
+        pub fn date_template(&self) -> &'static str {
+            match self {
+                Language::English => "{{greeting}} {{username}}.{{pause}}\nToday is {{weekday}}, the {{date iso_date spoken_date}}.{{pause}}\nThe time is {{time time}}.",
+                Language::German => "{{greeting}} {{username}}.{{pause}}\nHeute ist {{weekday}}, der {{date iso_date spoken_date}}.{{pause}}\nEs ist {{time time}} Uhr.",
+            }
+        }
+
+        /// Built-in template for the list of events
+        pub fn briefing_template(&self) -> &'static str {
+            match self {
+                Language::English => concat!(
+                    "Hey {{username}}! You have {{count}} {{plural count \"event\" \"events\"}} due today.{{pause 800}}\n",
+                    "{{#each events}}",
+                    "Number {{number}}: {{description}}.\n",
+                    "{{#if (or location timepoint)}}Taking place{{#if location}} at {{location}}{{/if}}{{#if timepoint}} at {{time timepoint}}{{/if}}.\n{{/if}}",
+                    "{{pause}}",
+                    "{{/each}}",
+                    "{{#if stale}}Your calendar could not be updated, so this may be out of date.\n{{/if}}",
+                ),
+                Language::German => concat!(
+                    "Hallo {{username}}! Du hast heute {{count}} {{plural count \"Termin\" \"Termine\"}}.{{pause 800}}\n",
+                    "{{#each events}}",
+                    "Nummer {{number}}: {{description}}.\n",
+                    "{{#if (or location timepoint)}}Findet statt{{#if location}} in {{location}}{{/if}}{{#if timepoint}} um {{time timepoint}}{{/if}}.\n{{/if}}",
+                    "{{pause}}",
+                    "{{/each}}",
+                    "{{#if stale}}Dein Kalender konnte nicht aktualisiert werden, die Termine sind vielleicht nicht aktuell.\n{{/if}}",
+                ),
+            }
+        }
+    }
+
+    /// A text to be spoken, optionally also marked up as SSML
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Utterance {
+        pub plain: String,
+        pub ssml: Option<String>,
+    }
+
+    impl Utterance {
+        /// The text an engine should be given, SSML only if it understands it
+        pub fn text_for(&self, engine: &impl TtsEngine) -> &str {
+            match &self.ssml {
+                Some(ssml) if engine.supports_ssml() => ssml,
+                _ => &self.plain,
+            }
+        }
+    }
+
+    impl fmt::Display for Utterance {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.plain)
+        }
+    }
+
+    /// Something that turns text into audio
+    pub trait TtsEngine {
+        /// File extension of the audio produced by this engine
+        fn extension(&self, speech: &SpeechConfig) -> String;
+
+        /// Whether the engine accepts SSML instead of plain text
+        fn supports_ssml(&self) -> bool {
+            false
+        }
+
+        fn synthesize(&self, text: &str, speech: &SpeechConfig) -> impl Future<Output = Result<Bytes, TtsError>> + Send;
+    }
+
+    /// The available text to speech backends as they appear in the config
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "engine")]
+    pub enum TtsBackend {
+        /// The VoiceRSS web service
+        VoiceRss {
+            api_key: String,
+            #[serde(default)]
+            ssml: bool,
+        },
+        /// A local program like `espeak-ng` or `piper`. `{text}` and `{output}` in
+        /// `args` are replaced with the text and a temporary output file. Without
+        /// `{text}` the text is written to stdin, without `{output}` the audio is
+        /// read from stdout. `{language}`, `{voice}` and `{rate}` are replaced with
+        /// the speech settings.
+        Command {
+            program: String,
+            args: Vec<String>,
+            extension: String,
+            #[serde(default)]
+            ssml: bool,
+        },
+        /// Any HTTP service returning audio. `{text}` in `url` is replaced with the
+        /// url-encoded text, with `post` set the text is sent as the request body.
+        Http {
+            url: String,
+            post: bool,
+            extension: String,
+            #[serde(default)]
+            ssml: bool,
+        },
+    }
+
+    impl ::std::default::Default for TtsBackend {
+        fn default() -> Self {
+            TtsBackend::VoiceRss {
+                api_key: "YOUR TTS API KEY".to_string(),
+                ssml: false,
+            }
+        }
+    }
+
+    impl fmt::Display for TtsBackend {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TtsBackend::VoiceRss { .. } => write!(f, "VoiceRSS"),
+                TtsBackend::Command { program, .. } => write!(f, "{}", program),
+                TtsBackend::Http { url, .. } => write!(f, "{}", url),
+            }
+        }
+    }
+
+    impl TtsEngine for TtsBackend {
+        fn extension(&self, speech: &SpeechConfig) -> String {
+            match self {
+                TtsBackend::VoiceRss { .. } => speech.codec_extension(),
+                TtsBackend::Command { extension, .. } => extension.clone(),
+                TtsBackend::Http { extension, .. } => extension.clone(),
+            }
+        }
+
+        fn supports_ssml(&self) -> bool {
+            match self {
+                TtsBackend::VoiceRss { ssml, .. } => *ssml,
+                TtsBackend::Command { ssml, .. } => *ssml,
+                TtsBackend::Http { ssml, .. } => *ssml,
+            }
+        }
+
+        async fn synthesize(&self, text: &str, speech: &SpeechConfig) -> Result<Bytes, TtsError> {
+            match self {
+                TtsBackend::VoiceRss { api_key, ssml } => Ok(get_speech_voicerss(text, api_key, *ssml, speech).await?),
+                TtsBackend::Command { program, args, extension, .. } => {
+                    get_speech_command(text, program, args, extension, speech).await
+                }
+                TtsBackend::Http { url, post, .. } => get_speech_http(text, url, *post).await,
+            }
+        }
+    }
+
+    /// Tries every engine in order and returns the audio of the first one that
+    /// succeeds, together with the engine that produced it.
+    pub async fn synthesize_with_fallback<'e>(engines: &'e [TtsBackend], utterance: &Utterance, speech: &SpeechConfig) -> Result<(Bytes, &'e TtsBackend), TtsError> {
+        let mut last_err = TtsError::Engine("No TTS engine configured".to_string());
+        for engine in engines {
+            match engine.synthesize(utterance.text_for(engine), speech).await {
+                Ok(audio) if !audio.is_empty() => return Ok((audio, engine)),
+                Ok(_) => {
+                    log::warn!("TTS engine {} returned no audio", engine);
+                    last_err = TtsError::Engine(format!("{} returned no audio", engine));
+                }
+                Err(err) => {
+                    log::warn!("TTS engine {} failed: {}", engine, err);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    pub async fn get_speech_voicerss(text: &str, tts_api_key: &str, ssml: bool, speech: &SpeechConfig) -> Result<Bytes, reqwest::Error> {
         let client = reqwest::Client::builder().use_rustls_tls().build()?;
-        let language = "en-gb";
-        return client.get(format!(
-            "http://api.voicerss.org/?key={}&hl={}&c=MP3&f=48khz_16bit_stereo&v=Nancy&src={}",
-            tts_api_key, language, text
-        )).send()
-        .await?
-        .bytes()
-        .await;
-    }
-
-    fn get_daytime_from_time<'t>(time: NaiveTime) -> &'t str {
-        if time > NaiveTime::from_hms(18, 30, 0) {
-            "Evening"
-        } else if time > NaiveTime::from_hms(15, 30, 0) {
-            "Afternoon"
-        } else if time > NaiveTime::from_hms(11, 30, 0) {
-            "Noon"
-        } else if time > NaiveTime::from_hms(5, 30, 0) {
-            "Morning"
+        client.get("http://api.voicerss.org/")
+            .query(&[
+                ("key", tts_api_key),
+                ("hl", &speech.language),
+                ("c", &speech.codec),
+                ("f", &speech.sample_rate),
+                ("v", &speech.voice),
+                ("r", &speech.rate.clamp(-10, 10).to_string()),
+                ("ssml", if ssml { "true" } else { "false" }),
+                ("src", text),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    }
+
+    async fn get_speech_command(text: &str, program: &str, args: &[String], extension: &str, speech: &SpeechConfig) -> Result<Bytes, TtsError> {
+        // Syntheses running at the same time each need their own file
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let output_file = std::env::temp_dir().join(format!(
+            "freemind-sonos-tts-{}-{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            extension
+        ));
+        let output_str = output_file.to_string_lossy().to_string();
+
+        let text_in_args = args.iter().any(|a| a.contains("{text}"));
+        let output_in_args = args.iter().any(|a| a.contains("{output}"));
+        let args: Vec<String> = args
+            .iter()
+            .map(|a| {
+                a.replace("{text}", text)
+                    .replace("{output}", &output_str)
+                    .replace("{language}", &speech.language)
+                    .replace("{voice}", &speech.voice)
+                    .replace("{rate}", &speech.rate.to_string())
+            })
+            .collect();
+
+        let mut child = Command::new(program)
+            .args(&args)
+            .stdin(if text_in_args { Stdio::null() } else { Stdio::piped() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Engines may exit without reading the text, their exit status tells whether that is a problem
+            match stdin.write_all(text.as_bytes()).await {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(TtsError::Engine(format!(
+                "{} exited with {}: {}",
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        if output_in_args {
+            let audio = tokio::fs::read(&output_file).await?;
+            tokio::fs::remove_file(&output_file).await.unwrap_or(());
+            Ok(Bytes::from(audio))
         } else {
-            "Tag"
-        }
-    }
-
-    pub async fn get_date(user: String, timezone: i8, tts_api_key: &str) -> Result<Bytes, reqwest::Error> {
-        let time = Utc::now()
-            .checked_add_signed(Duration::hours(timezone as i64))
-            .unwrap_or(Utc::now());
-        let text = format!(
-            "Good {} {}. \n Today is {}, the {} {} {}. \n The time is {}.",
-            get_daytime_from_time(time.time()),
-            user,
-            &time.format("%A").to_string(),
-            time.format("%d"),
-            &time.format("%B").to_string(),
-            time.format("%Y"),
-            time.format("%H:%M")
-        );
-        get_speech_voicerss(&text, tts_api_key).await
+            Ok(Bytes::from(output.stdout))
+        }
+    }
+
+    async fn get_speech_http(text: &str, url: &str, post: bool) -> Result<Bytes, TtsError> {
+        let client = reqwest::Client::builder().use_rustls_tls().build()?;
+        let request = if post {
+            client.post(url).body(text.to_string())
+        } else {
+            let encoded: String = url::form_urlencoded::byte_serialize(text.as_bytes()).collect();
+            client.get(url.replace("{text}", &encoded))
+        };
+        Ok(request.send().await?.error_for_status()?.bytes().await?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn command(program: &str, args: &[&str], extension: &str) -> TtsBackend {
+            TtsBackend::Command {
+                program: program.to_string(),
+                args: args.iter().map(|a| a.to_string()).collect(),
+                extension: extension.to_string(),
+                ssml: false,
+            }
+        }
+
+        fn speak(text: &str) -> Utterance {
+            Utterance { plain: text.to_string(), ssml: None }
+        }
+
+        #[tokio::test]
+        async fn commands_take_the_text_as_argument_or_on_stdin() {
+            let speech = SpeechConfig::default();
+            let from_args = command("sh", &["-c", "printf '%s|%s' \"$0\" \"$1\"", "{text}", "{language}"], "raw");
+            assert_eq!(from_args.synthesize("Good morning", &speech).await.unwrap(), Bytes::from("Good morning|en-gb"));
+            let from_stdin = command("cat", &[], "raw");
+            assert_eq!(from_stdin.synthesize("Good morning", &speech).await.unwrap(), Bytes::from("Good morning"));
+        }
+
+        #[tokio::test]
+        async fn commands_write_to_the_output_file_or_stdout() {
+            let speech = SpeechConfig::default();
+            let to_file = command("sh", &["-c", "cat > \"$0\"", "{output}"], "wav");
+            assert_eq!(to_file.synthesize("Hello", &speech).await.unwrap(), Bytes::from("Hello"));
+            let args_to_file = command("sh", &["-c", "printf '%s' \"$0\" > \"$1\"", "{text}", "{output}"], "wav");
+            assert_eq!(args_to_file.synthesize("Hi there", &speech).await.unwrap(), Bytes::from("Hi there"));
+        }
+
+        #[tokio::test]
+        async fn syntheses_at_the_same_time_get_their_own_files() {
+            let speech = SpeechConfig::default();
+            let slow = command("sh", &["-c", "printf '%s' \"$0\" > \"$1\"; sleep 0.2", "{text}", "{output}"], "mp3");
+            let (first, second) = tokio::join!(slow.synthesize("Briefing", &speech), slow.synthesize("Preview", &speech));
+            assert_eq!(first.unwrap(), Bytes::from("Briefing"));
+            assert_eq!(second.unwrap(), Bytes::from("Preview"));
+        }
+
+        #[tokio::test]
+        async fn failing_commands_are_errors() {
+            let failing = command("sh", &["-c", "echo broken >&2; exit 3"], "raw");
+            match failing.synthesize("Hello", &SpeechConfig::default()).await {
+                Err(TtsError::Engine(msg)) => assert!(msg.contains("broken"), "{}", msg),
+                other => panic!("Expected an engine error, got {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn falls_back_to_the_next_engine() {
+            let speech = SpeechConfig::default();
+            let engines = vec![
+                command("sh", &["-c", "exit 1"], "raw"),
+                command("true", &[], "raw"),
+                command("cat", &[], "wav"),
+            ];
+            let (audio, engine) = synthesize_with_fallback(&engines, &speak("Still here"), &speech).await.unwrap();
+            assert_eq!(audio, Bytes::from("Still here"));
+            assert_eq!(engine.extension(&speech), "wav");
+
+            match synthesize_with_fallback(&engines[..2], &speak("Gone"), &speech).await {
+                Err(TtsError::Engine(msg)) => assert_eq!(msg, "true returned no audio"),
+                other => panic!("Expected the last error, got {:?}", other),
+            }
+            assert!(synthesize_with_fallback(&[], &speak("Nobody"), &speech).await.is_err());
+        }
+    }
+}
+
+pub mod template {
+    use super::speech::{Language, Utterance};
+    use crate::freemind::freemind_handler::AppElement;
+    use crate::recurrence::recurrence_rule::Occurrence;
+    use chrono::{DateTime, Datelike, TimeZone, Timelike};
+    use handlebars::{
+        handlebars_helper, html_escape, no_escape, Context, Handlebars, Helper, HelperResult,
+        JsonRender, Output, RenderContext, RenderError,
+    };
+    use serde::Serialize;
+    use std::path::Path;
+
+    const BRIEFING: &str = "briefing";
+    const DATE: &str = "date";
+
+    handlebars_helper!(plural: |count: u64, one: str, many: str| if count == 1 { one } else { many });
+
+    fn param(h: &Helper, index: usize) -> String {
+        h.param(index).map(|p| p.value().render()).unwrap_or_default()
+    }
+
+    fn plain_pause(_: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write("\n")?;
+        Ok(())
+    }
+
+    fn ssml_pause(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        let ms = h.param(0).and_then(|p| p.value().as_u64()).unwrap_or(500);
+        out.write(&format!("<break time=\"{}ms\"/>", ms))?;
+        Ok(())
+    }
+
+    fn plain_value(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write(&param(h, 0))?;
+        Ok(())
+    }
+
+    fn ssml_emphasis(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write(&format!("<emphasis>{}</emphasis>", html_escape(&param(h, 0))))?;
+        Ok(())
+    }
+
+    fn ssml_time(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write(&format!(
+            "<emphasis><say-as interpret-as=\"time\" format=\"hms24\">{}</say-as></emphasis>",
+            html_escape(&param(h, 0))
+        ))?;
+        Ok(())
+    }
+
+    /// `{{date iso spoken}}` reads `spoken` in plain text and `iso` as an SSML date
+    fn plain_date(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write(&param(h, 1))?;
+        Ok(())
+    }
+
+    fn ssml_date(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+        out.write(&format!(
+            "<say-as interpret-as=\"date\" format=\"ymd\">{}</say-as>",
+            html_escape(&param(h, 0))
+        ))?;
+        Ok(())
+    }
+
+    /// Creates a registry whose markup helpers (`pause`, `emphasis`, `time` and
+    /// `date`) either produce SSML or plain text
+    fn registry(ssml: bool) -> Handlebars<'static> {
+        let mut registry = Handlebars::new();
+        registry.register_helper("plural", Box::new(plural));
+        if ssml {
+            registry.register_escape_fn(html_escape);
+            registry.register_helper("pause", Box::new(ssml_pause));
+            registry.register_helper("emphasis", Box::new(ssml_emphasis));
+            registry.register_helper("time", Box::new(ssml_time));
+            registry.register_helper("date", Box::new(ssml_date));
+        } else {
+            registry.register_escape_fn(no_escape);
+            registry.register_helper("pause", Box::new(plain_pause));
+            registry.register_helper("emphasis", Box::new(plain_value));
+            registry.register_helper("time", Box::new(plain_value));
+            registry.register_helper("date", Box::new(plain_date));
+        }
+        registry
+    }
+
+    /// Variables available to `date.hbs`
+    #[derive(Debug, Clone, Serialize)]
+    pub struct DateContext {
+        pub greeting: String,
+        pub username: String,
+        pub weekday: String,
+        pub day: u32,
+        pub month: String,
+        pub year: i32,
+        pub iso_date: String,
+        pub spoken_date: String,
+        pub time: String,
+    }
+    /// Variables available for every entry of `events` in `briefing.hbs`
+    #[derive(Debug, Clone, Serialize)]
+    pub struct EventContext {
+        pub number: usize,
+        pub title: String,
+        pub description: String,
+        pub location: String,
+        /// When the event takes place, not when preparing for it starts
+        pub timepoint: Option<String>,
+        pub preparation: Option<String>,
+    }
+
+    /// Variables available to `briefing.hbs`
+    #[derive(Debug, Clone, Serialize)]
+    pub struct BriefingContext {
+        pub username: String,
+        pub count: usize,
+        pub events: Vec<EventContext>,
+        /// The events could not be refreshed and may be out of date
+        pub stale: bool,
+    }
+
+    impl BriefingContext {
+        pub fn new(username: &str, elements: &[(&AppElement, Occurrence)], stale: bool) -> Self {
+            Self {
+                username: username.to_string(),
+                count: elements.len(),
+                stale,
+                events: elements
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (e, occurrence))| EventContext {
+                        number: i + 1,
+                        title: e.title().to_string(),
+                        description: e.description().to_string(),
+                        location: e.location().to_string(),
+                        timepoint: occurrence.event_time(),
+                        preparation: e.preparation_description().map(|p| p.to_string()),
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    /// The templates used to phrase announcements. Every template can be replaced
+    /// by a `<name>.hbs` file in the template directory, otherwise the built-in one
+    /// for the configured language is used.
+    /// Every template is rendered twice, once as plain text and once as SSML, so
+    /// each engine can be given what it understands.
+    #[derive(Debug, Clone)]
+    pub struct Templates {
+        language: Language,
+        plain: Handlebars<'static>,
+        ssml: Handlebars<'static>,
+    }
+
+    impl Templates {
+        /// Uses only the built-in templates of `language`
+        pub fn new(language: Language) -> Self {
+            let mut templates = Self {
+                language,
+                plain: registry(false),
+                ssml: registry(true),
+            };
+            templates
+                .register(BRIEFING, language.briefing_template())
+                .expect("Built-in briefing template is invalid");
+            templates
+                .register(DATE, language.date_template())
+                .expect("Built-in date template is invalid");
+            templates
+        }
+
+        fn register(&mut self, name: &str, template: &str) -> Result<(), Box<handlebars::TemplateError>> {
+            self.plain.register_template_string(name, template)?;
+            self.ssml.register_template_string(name, template)?;
+            Ok(())
+        }
+
+        /// Uses the templates found in `dir`, falling back to the built-in ones
+        pub fn load(dir: &Path, language: Language) -> Result<Self, Box<handlebars::TemplateError>> {
+            let mut templates = Self::new(language);
+            for name in [BRIEFING, DATE] {
+                let file = dir.join(format!("{}.hbs", name));
+                if !file.is_file() {
+                    continue;
+                }
+                let result = match std::fs::read_to_string(&file) {
+                    Ok(template) => templates.register(name, &template).map_err(|e| e.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                match result {
+                    Ok(()) => log::debug!("Using template {}", file.display()),
+                    Err(err) => {
+                        log::error!("Ignoring invalid template {}: {}", file.display(), err);
+                        templates.register(name, match name {
+                            BRIEFING => language.briefing_template(),
+                            _ => language.date_template(),
+                        })?;
+                    }
+                }
+            }
+            Ok(templates)
+        }
+
+        /// The template rendered as plain text and as the body of an SSML document
+        fn render_parts<T: Serialize>(&self, name: &str, context: &T) -> Result<(String, String), RenderError> {
+            Ok((self.plain.render(name, context)?, self.ssml.render(name, context)?))
+        }
+
+        fn render<T: Serialize>(&self, name: &str, context: &T) -> Result<Utterance, RenderError> {
+            let (plain, ssml) = self.render_parts(name, context)?;
+            Ok(Utterance {
+                plain,
+                ssml: Some(format!("<speak>{}</speak>", ssml)),
+            })
+        }
+
+        pub fn render_briefing(&self, context: &BriefingContext) -> Result<Utterance, RenderError> {
+            self.render(BRIEFING, context)
+        }
+
+        /// The greeting with the date and `time`, followed by the briefing
+        pub fn render_wake_up<T: TimeZone>(&self, time: &DateTime<T>, context: &BriefingContext) -> Result<Utterance, RenderError> {
+            let (date_plain, date_ssml) = self.render_parts(DATE, &self.date_context(&context.username, time))?;
+            let (plain, ssml) = self.render_parts(BRIEFING, context)?;
+            Ok(Utterance {
+                plain: format!("{}\n{}", date_plain, plain),
+                ssml: Some(format!("<speak>{}{}</speak>", date_ssml, ssml)),
+            })
+        }
+
+        fn date_context<T: TimeZone>(&self, user: &str, time: &DateTime<T>) -> DateContext {
+            let month = time.month0() as usize;
+            DateContext {
+                greeting: self.language.greeting(time.time()).to_string(),
+                username: user.to_string(),
+                weekday: self.language.weekday(time.weekday().num_days_from_monday() as usize).to_string(),
+                day: time.day(),
+                month: self.language.month(month).to_string(),
+                year: time.year(),
+                iso_date: format!("{:04}-{:02}-{:02}", time.year(), time.month(), time.day()),
+                spoken_date: self.language.spoken_date(time.day(), month, time.year()),
+                time: format!("{:02}:{:02}", time.hour(), time.minute()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::time_source::local_time_zone;
+        use chrono::Utc;
+        use std::fs;
+
+        fn briefing(stale: bool) -> BriefingContext {
+            BriefingContext {
+                username: "Ann".to_string(),
+                count: 2,
+                stale,
+                events: vec![
+                    EventContext {
+                        number: 1,
+                        title: "Dentist".to_string(),
+                        description: "Go to the dentist".to_string(),
+                        location: "Main Street".to_string(),
+                        timepoint: Some("09:30".to_string()),
+                        preparation: None,
+                    },
+                    EventContext {
+                        number: 2,
+                        title: "Plants".to_string(),
+                        description: "Water the plants".to_string(),
+                        location: String::new(),
+                        timepoint: None,
+                        preparation: None,
+                    },
+                ],
+            }
+        }
+
+        #[test]
+        fn renders_english_briefing() {
+            let text = Templates::new(Language::English).render_briefing(&briefing(false)).unwrap();
+            assert_eq!(
+                text.plain,
+                "Hey Ann! You have 2 events due today.\n\n\
+                 Number 1: Go to the dentist.\nTaking place at Main Street at 09:30.\n\n\
+                 Number 2: Water the plants.\n\n"
+            );
+            let ssml = text.ssml.unwrap();
+            assert!(ssml.starts_with("<speak>Hey Ann! You have 2 events due today.<break time=\"800ms\"/>"));
+            assert!(ssml.contains("at <emphasis><say-as interpret-as=\"time\" format=\"hms24\">09:30</say-as></emphasis>."));
+        }
+
+        #[test]
+        fn renders_german_briefing() {
+            let mut context = briefing(true);
+            context.count = 1;
+            context.events.truncate(1);
+            let text = Templates::new(Language::German).render_briefing(&context).unwrap();
+            assert_eq!(
+                text.plain,
+                "Hallo Ann! Du hast heute 1 Termin.\n\n\
+                 Nummer 1: Go to the dentist.\nFindet statt in Main Street um 09:30.\n\n\
+                 Dein Kalender konnte nicht aktualisiert werden, die Termine sind vielleicht nicht aktuell.\n"
+            );
+        }
+
+        /// Two elements of 2024-03-05 in Europe/Berlin, the first at 09:30 with half
+        /// an hour of preparation, the second at 12:00 without a location
+        const ELEMENTS: [&str; 2] = [
+            "<entry id=\"1\"><name>Dentist</name><description>Go to the dentist</description><due>1709627400</due>\
+             <preparation><description>Brush your teeth</description><time>30</time></preparation>\
+             <location>Main Street</location><alert>default</alert></entry>",
+            "<entry id=\"2\"><name>Lunch</name><description>Meet Bob</description><due>1709636400</due></entry>",
+        ];
+
+        #[test]
+        fn renders_briefing_of_elements() {
+            let _tz = local_time_zone("Europe/Berlin");
+            let elements: Vec<AppElement> = ELEMENTS.iter().map(|xml| quick_xml::de::from_str(xml).unwrap()).collect();
+            let occurrences: Vec<(&AppElement, Occurrence)> = elements
+                .iter()
+                .map(|e| (e, e.occurrences(1_709_593_200, 1_709_679_600).remove(0)))
+                .collect();
+            assert_eq!(occurrences[0].1.prepare, 1_709_625_600);
+
+            let context = BriefingContext::new("Ann", &occurrences, false);
+            assert_eq!(context.count, 2);
+            // The event time is read out, not when preparing for it starts
+            assert_eq!(context.events[0].timepoint.as_deref(), Some("09:30"));
+            assert_eq!(context.events[0].preparation.as_deref(), Some("Brush your teeth"));
+            assert_eq!(context.events[1].location, "");
+            assert_eq!(context.events[1].preparation, None);
+
+            let text = Templates::new(Language::English).render_briefing(&context).unwrap();
+            assert_eq!(
+                text.plain,
+                "Hey Ann! You have 2 events due today.\n\n\
+                 Number 1: Go to the dentist.\nTaking place at Main Street at 09:30.\n\n\
+                 Number 2: Meet Bob.\nTaking place at 12:00.\n\n"
+            );
+        }
+
+        /// The greeting of Ann at `time`
+        fn date<T: TimeZone>(templates: &Templates, time: &DateTime<T>) -> Utterance {
+            templates.render(DATE, &templates.date_context("Ann", time)).unwrap()
+        }
+
+        #[test]
+        fn renders_english_date() {
+            let time = Utc.with_ymd_and_hms(2024, 3, 5, 8, 7, 0).unwrap();
+            let text = date(&Templates::new(Language::English), &time);
+            assert_eq!(
+                text.plain,
+                "Good Morning Ann.\n\nToday is Tuesday, the 5 March 2024.\n\nThe time is 08:07."
+            );
+            assert!(text.ssml.unwrap().contains("<say-as interpret-as=\"date\" format=\"ymd\">2024-03-05</say-as>"));
+        }
+
+        #[test]
+        fn renders_german_date() {
+            let time = Utc.with_ymd_and_hms(2024, 3, 5, 19, 45, 0).unwrap();
+            let text = date(&Templates::new(Language::German), &time);
+            assert_eq!(
+                text.plain,
+                "Guten Abend Ann.\n\nHeute ist Dienstag, der 5. März 2024.\n\nEs ist 19:45 Uhr."
+            );
+        }
+
+        #[test]
+        fn greets_before_the_briefing() {
+            let time = Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap();
+            let templates = Templates::new(Language::English);
+            let text = templates.render_wake_up(&time, &briefing(false)).unwrap();
+            assert_eq!(
+                text.plain,
+                format!(
+                    "{}\n{}",
+                    date(&templates, &time).plain,
+                    templates.render_briefing(&briefing(false)).unwrap().plain
+                )
+            );
+            assert!(text.plain.starts_with("Good Morning Ann.\n\nToday is Tuesday, the 5 March 2024.\n\nThe time is 07:00.\nHey Ann!"));
+            let ssml = text.ssml.unwrap();
+            assert!(ssml.starts_with("<speak>Good Morning Ann.<break time=\"500ms\"/>"));
+            assert_eq!(ssml.matches("<speak>").count(), 1);
+            assert!(ssml.ends_with("</speak>"));
+        }
+
+        #[test]
+        fn falls_back_on_invalid_templates() {
+            let dir = std::env::temp_dir().join(format!("freemind-sonos-templates-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("briefing.hbs"), "{{#each events}}unclosed").unwrap();
+            fs::write(dir.join("date.hbs"), "Hi {{username}}").unwrap();
+            let templates = Templates::load(&dir, Language::English);
+            fs::remove_dir_all(&dir).unwrap();
+
+            let templates = templates.unwrap();
+            let fallback = Templates::new(Language::English);
+            assert_eq!(
+                templates.render_briefing(&briefing(false)).unwrap(),
+                fallback.render_briefing(&briefing(false)).unwrap()
+            );
+            let time = Utc.with_ymd_and_hms(2024, 3, 5, 8, 7, 0).unwrap();
+            assert_eq!(date(&templates, &time).plain, "Hi Ann");
+        }
     }
 }
 
 pub mod music {
+    use std::collections::BTreeMap;
     use std::fmt;
+    use std::fs;
+    use std::future::Future;
+    use std::path::{Component, Path, PathBuf};
 
-    use rand::seq::SliceRandom;
+    use crate::clock::time_source::SharedClock;
+    use crate::server::media_server::mime_type;
+    use crate::didl::didl_lite::{DidlItem, AUDIO_BROADCAST, MUSIC_TRACK};
+    use crate::store::json_store::{JsonStore, Records};
+    use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC};
+    use rand::distributions::WeightedIndex;
+    use rand::prelude::Distribution;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use serde::{Deserialize, Serialize};
     use reqwest;
 
@@ -64,8 +907,30 @@ pub mod music {
         #[serde(rename = "AlbumArtist")]
         pub artist: String,
         pub album: Option<String>,
-        #[serde(rename = "RunTimeTicks")]
-        pub ticks: u64
+        #[serde(rename = "RunTimeTicks", default)]
+        pub ticks: u64,
+        #[serde(default)]
+        pub user_data: Option<UserData>,
+    }
+
+    /// Per user information Jellyfin keeps about an item
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct UserData {
+        #[serde(default)]
+        pub is_favorite: bool,
+        #[serde(default)]
+        pub play_count: u32,
+    }
+
+    /// Jellyfin measures time in ticks of 100 nanoseconds
+    pub const TICKS_PER_SECOND: u64 = 10_000_000;
+
+    impl Title {
+        /// Length of the track in seconds
+        pub fn duration(&self) -> u64 {
+            self.ticks / TICKS_PER_SECOND
+        }
     }
 
     impl fmt::Display for Title {
@@ -81,11 +946,19 @@ pub mod music {
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
     pub struct JellyfinConfig {
         server: String,
         api_key: String,
         playlist_id: String,
         user_id: String,
+        /// Format the tracks are transcoded to, e.g. mp3, aac or flac
+        container: String,
+        /// Codec inside the container, e.g. vorbis for ogg. Jellyfin picks one
+        /// that fits the container if unset.
+        codec: Option<String>,
+        /// Bitrate of the transcoded stream in kbit/s
+        bitrate: u32,
     }
 
     impl ::std::default::Default for JellyfinConfig {
@@ -95,29 +968,884 @@ pub mod music {
                 api_key: "YOUR API KEY".to_string(),
                 playlist_id: "id of the playlist to use".to_string(),
                 user_id: "id of the user".to_string(),
+                container: "mp3".to_string(),
+                codec: None,
+                bitrate: 192,
             }
         }
     }
 
-    pub async fn get_random_jellyfin_track(config: &JellyfinConfig) -> Result<Option<Title>, reqwest::Error> {
-        let client: reqwest::Client = reqwest::Client::builder().use_rustls_tls().build()?;
-        let playlist: Playlist = client.get(
+    impl fmt::Display for JellyfinConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
+
+    impl JellyfinConfig {
+        fn server(&self) -> &str {
+            self.server.trim_end_matches('/')
+        }
+
+        /// URL of the cover of `title`
+        pub fn image_url(&self, title: &Title) -> String {
+            format!(
+                "{}/Items/{}/Images/Primary?api_key={}",
+                self.server(),
+                utf8_percent_encode(&title.id, NON_ALPHANUMERIC),
+                utf8_percent_encode(&self.api_key, NON_ALPHANUMERIC),
+            )
+        }
+
+        /// URL the speaker can stream `title` from, `Track::uri_at` starts it later on
+        pub fn stream_url(&self, title: &Title) -> String {
+            let mut url = format!(
+                "{}/Audio/{}/stream.{}?api_key={}&audioBitRate={}",
+                self.server(),
+                utf8_percent_encode(&title.id, NON_ALPHANUMERIC),
+                self.container,
+                utf8_percent_encode(&self.api_key, NON_ALPHANUMERIC),
+                self.bitrate as u64 * 1000,
+            );
+            if let Some(codec) = &self.codec {
+                url.push_str(&format!("&audioCodec={}", utf8_percent_encode(codec, NON_ALPHANUMERIC)));
+            }
+            url
+        }
+
+        /// URL listing the items of the playlist `id`
+        fn playlist_url(&self, id: &str) -> String {
             format!(
                 "{}/Playlists/{}/Items?api_key={}&userId={}",
-                config.server,
-                config.playlist_id,
-                config.api_key,
-                config.user_id
+                self.server(),
+                utf8_percent_encode(id, NON_ALPHANUMERIC),
+                utf8_percent_encode(&self.api_key, NON_ALPHANUMERIC),
+                utf8_percent_encode(&self.user_id, NON_ALPHANUMERIC),
             )
-        )
+        }
+    }
+
+    /// Fetches the items of a playlist, `playlist_id` overrides the configured one
+    pub async fn get_jellyfin_playlist(config: &JellyfinConfig, playlist_id: Option<&str>) -> Result<Vec<Title>, reqwest::Error> {
+        let client: reqwest::Client = reqwest::Client::builder().use_rustls_tls().build()?;
+        let playlist: Playlist = client.get(config.playlist_url(playlist_id.unwrap_or(&config.playlist_id)))
             .send()
             .await?
+            .error_for_status()?
             .json::<Playlist>()
             .await?;
 
-        return match playlist.items.choose(&mut rand::thread_rng()) {
-            Some(title) => Ok(Some(title.clone())),
-            None => Ok(None)
+        Ok(playlist.items)
+    }
+
+    /// How the wake-up track is picked among the candidates
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Strategy {
+        /// Every track is equally likely
+        Uniform,
+        /// Favourites and often played tracks are more likely
+        Weighted,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct SelectionConfig {
+        strategy: Strategy,
+        /// Tracks played within this many days are not picked again
+        avoid_days: u16,
+        /// How many times more likely favourites are picked
+        favourite_weight: u32,
+        /// Makes the picks reproducible
+        seed: Option<u64>,
+    }
+
+    impl ::std::default::Default for SelectionConfig {
+        fn default() -> Self {
+            Self {
+                strategy: Strategy::Uniform,
+                avoid_days: 3,
+                favourite_weight: 3,
+                seed: None,
+            }
+        }
+    }
+
+    impl fmt::Display for SelectionConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
+
+    impl SelectionConfig {
+        pub fn rng(&self) -> StdRng {
+            match self.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }
+        }
+
+        fn weight(&self, track: &Track) -> u32 {
+            match self.strategy {
+                Strategy::Uniform => 1,
+                Strategy::Weighted => {
+                    let favourite = if track.favourite { self.favourite_weight.max(1) } else { 1 };
+                    (1 + track.play_count.min(20)) * favourite
+                }
+            }
+        }
+    }
+
+    /// When each track was last played, keyed by the track id
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct Plays {
+        played: BTreeMap<String, u32>,
+    }
+
+    impl Records for Plays {
+        const RETENTION: u32 = 90 * 24 * 60 * 60;
+        const NAME: &'static str = "play history";
+
+        fn expire(&mut self, oldest: u32) {
+            self.played.retain(|_, at| *at >= oldest);
+        }
+    }
+
+    /// Remembers when each track was last played
+    pub type PlayHistory = JsonStore<Plays>;
+
+    impl Plays {
+        pub fn record(&mut self, id: &str, at: u32) {
+            self.played.insert(id.to_string(), at);
+        }
+    }
+
+    impl JsonStore<Plays> {
+        /// Whether `id` was played after `since`
+        pub fn played_since(&self, id: &str, since: u32) -> bool {
+            self.records().played.get(id).is_some_and(|at| *at >= since)
+        }
+    }
+
+    /// Picks one of `tracks` following `selection`. Tracks played recently and
+    /// tracks shorter than `min_duration` seconds are skipped as long as any other
+    /// track is left.
+    pub fn select<'t, R: Rng>(
+        tracks: &'t [Track],
+        selection: &SelectionConfig,
+        history: &PlayHistory,
+        min_duration: u64,
+        now: u32,
+        rng: &mut R,
+    ) -> Option<&'t Track> {
+        let since = now.saturating_sub(selection.avoid_days as u32 * 24 * 60 * 60);
+        let long_enough = |t: &&Track| t.duration.is_none_or(|d| d == 0 || d > min_duration);
+        let not_recent = |t: &&Track| selection.avoid_days == 0 || !history.played_since(&t.id, since);
+
+        let mut candidates: Vec<&Track> = tracks.iter().filter(long_enough).filter(not_recent).collect();
+        if candidates.is_empty() {
+            candidates = tracks.iter().filter(long_enough).collect();
+        }
+        if candidates.is_empty() {
+            candidates = tracks.iter().collect();
+        }
+
+        let weights: Vec<u32> = candidates.iter().map(|t| selection.weight(t)).collect();
+        let idx = WeightedIndex::new(&weights).ok()?.sample(rng);
+        candidates.get(idx).copied()
+    }
+
+    #[derive(Debug)]
+    pub enum MusicError {
+        Http(reqwest::Error),
+        Io(std::io::Error),
+    }
+
+    impl fmt::Display for MusicError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                MusicError::Http(err) => write!(f, "Music request failed: {}", err),
+                MusicError::Io(err) => write!(f, "Failed to read music: {}", err),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for MusicError {
+        fn from(err: reqwest::Error) -> Self {
+            MusicError::Http(err)
+        }
+    }
+
+    impl From<std::io::Error> for MusicError {
+        fn from(err: std::io::Error) -> Self {
+            MusicError::Io(err)
+        }
+    }
+
+    /// How playback of a track can continue at a later position
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Resume {
+        /// Seek within the track after setting the URI
+        Seek,
+        /// Jellyfin streams are started at the position with `startTimeTicks`
+        StartTicks,
+        /// Live streams simply continue
+        Live,
+    }
+
+    /// A playable track as handed to the speaker
+    #[derive(Debug, Clone)]
+    pub struct Track {
+        /// Identifies the track in the play history
+        pub id: String,
+        pub title: String,
+        pub artist: Option<String>,
+        pub album: Option<String>,
+        /// URL of the cover
+        pub art: Option<String>,
+        /// Length in seconds, `None` for live streams
+        pub duration: Option<u64>,
+        pub uri: String,
+        pub mime: String,
+        pub resume: Resume,
+        pub favourite: bool,
+        pub play_count: u32,
+    }
+
+    impl Track {
+        fn from_title(title: &Title, config: &JellyfinConfig) -> Self {
+            let user_data = title.user_data.clone().unwrap_or_default();
+            Self {
+                id: title.id.clone(),
+                title: title.name.clone(),
+                artist: Some(title.artist.clone()),
+                album: title.album.clone(),
+                art: Some(config.image_url(title)),
+                // Items Jellyfin has not scanned yet come without a length
+                duration: Some(title.duration()).filter(|d| *d > 0),
+                uri: config.stream_url(title),
+                mime: mime_type(Path::new(&format!("stream.{}", config.container))).to_string(),
+                resume: Resume::StartTicks,
+                favourite: user_data.is_favorite,
+                play_count: user_data.play_count,
+            }
+        }
+
+        /// URI to continue the track `offset` seconds in. For `Resume::Seek` the
+        /// speaker has to seek to the offset itself.
+        pub fn uri_at(&self, offset: u64) -> String {
+            match self.resume {
+                Resume::StartTicks if offset > 0 => format!("{}&startTimeTicks={}", self.uri, offset * TICKS_PER_SECOND),
+                _ => self.uri.clone(),
+            }
+        }
+
+        /// DIDL-Lite description of the track shown by the Sonos app
+        pub fn metadata(&self) -> String {
+            DidlItem::new(&self.uri, &self.title)
+                .class(if self.resume == Resume::Live { AUDIO_BROADCAST } else { MUSIC_TRACK })
+                .mime(&self.mime)
+                .artist(self.artist.as_deref())
+                .album(self.album.as_deref())
+                .album_art(self.art.as_deref())
+                .duration(self.duration)
+                .build()
+        }
+    }
+
+    /// Where local music is found and under which URL the speaker reaches it
+    #[derive(Debug, Clone)]
+    pub struct MusicContext {
+        /// The media directory
+        pub root: PathBuf,
+        /// URL the media directory is served under
+        pub base_url: String,
+        pub jellyfin: JellyfinConfig,
+        pub selection: SelectionConfig,
+        pub history: PlayHistory,
+        /// Shorter tracks are only picked if there is nothing else
+        pub min_duration: u64,
+        pub clock: SharedClock,
+    }
+
+    impl MusicContext {
+        /// Picks up to `count` different tracks of `tracks` with the configured strategy
+        pub fn select(&self, mut tracks: Vec<Track>, count: usize) -> Vec<Track> {
+            let now = self.clock.unix();
+            let mut rng = self.selection.rng();
+            let mut picked = Vec::new();
+            while picked.len() < count {
+                let id = match select(&tracks, &self.selection, &self.history, self.min_duration, now, &mut rng) {
+                    Some(track) => track.id.clone(),
+                    None => break,
+                };
+                if let Some(idx) = tracks.iter().position(|t| t.id == id) {
+                    picked.push(tracks.swap_remove(idx));
+                }
+            }
+            picked
+        }
+
+        /// Records that `track` is played now and saves the history
+        pub fn played(&mut self, track: &Track) {
+            let now = self.clock.unix();
+            if let Err(err) = self.history.update(now, |plays| plays.record(&track.id, now)) {
+                log::error!("Failed to save the play history: {}", err);
+            }
+        }
+
+        /// Path segments of a file inside the media directory, `None` for files outside of it
+        fn relative_path(&self, file: &Path) -> Option<Vec<String>> {
+            let relative = file.strip_prefix(&self.root).ok()?;
+            let mut segments: Vec<String> = Vec::new();
+            for component in relative.components() {
+                match component {
+                    Component::Normal(part) => segments.push(part.to_string_lossy().to_string()),
+                    Component::CurDir => {}
+                    // Playlists may refer to their neighbours, but not leave the media directory
+                    Component::ParentDir => {
+                        segments.pop()?;
+                    }
+                    _ => return None,
+                }
+            }
+            Some(segments)
+        }
+
+        /// URL of a file inside the media directory
+        fn url_of(&self, segments: &[String]) -> String {
+            let encoded: Vec<String> = segments.iter().map(|s| utf8_percent_encode(s, PATH).to_string()).collect();
+            format!("{}/{}", self.base_url.trim_end_matches('/'), encoded.join("/"))
+        }
+
+        /// URL of `file`, given relative to the media directory with `/` between directories
+        pub fn file_url(&self, file: &str) -> String {
+            let segments: Vec<String> = file.split('/').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+            self.url_of(&segments)
+        }
+
+        /// A file of the media directory, identified by its path inside it so the
+        /// history survives changes of the server address
+        fn local_track(&self, file: &Path, title: Option<String>, duration: Option<u64>) -> Option<Track> {
+            let segments = self.relative_path(file)?;
+            let uri = self.url_of(&segments);
+            Some(Track {
+                id: segments.join("/"),
+                title: title.unwrap_or_else(|| {
+                    file.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+                }),
+                artist: None,
+                album: None,
+                art: None,
+                duration,
+                uri,
+                mime: mime_type(file).to_string(),
+                resume: Resume::Seek,
+                favourite: false,
+                play_count: 0,
+            })
+        }
+    }
+
+    /// Characters escaped in path segments of local media URLs
+    const PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?').add(b'<').add(b'>').add(b'&');
+
+    pub trait MusicSource {
+        /// Name alarms use to select this source
+        fn name(&self) -> &str;
+        /// Every track the source offers to pick from
+        fn tracks(&self, ctx: &MusicContext) -> impl Future<Output = Result<Vec<Track>, MusicError>> + Send;
+    }
+
+    /// A configured source of wake-up music
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "source", rename_all = "lowercase")]
+    pub enum MusicBackend {
+        /// A random track of a Jellyfin playlist, by default the configured one
+        Jellyfin {
+            name: String,
+            playlist_id: Option<String>,
+        },
+        /// A random audio file of a directory inside the media directory
+        Directory {
+            name: String,
+            dir: PathBuf,
+        },
+        /// A random entry of a M3U or PLS playlist file. Local entries have to lie
+        /// inside the media directory.
+        Playlist {
+            name: String,
+            file: PathBuf,
+        },
+        /// An internet radio stream
+        Radio {
+            name: String,
+            url: String,
+            title: String,
+        },
+    }
+
+    impl ::std::default::Default for MusicBackend {
+        fn default() -> Self {
+            MusicBackend::Jellyfin {
+                name: "default".to_string(),
+                playlist_id: None,
+            }
+        }
+    }
+
+    impl fmt::Display for MusicBackend {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                MusicBackend::Jellyfin { name, .. } => write!(f, "{} (Jellyfin)", name),
+                MusicBackend::Directory { name, dir } => write!(f, "{} ({})", name, dir.display()),
+                MusicBackend::Playlist { name, file } => write!(f, "{} ({})", name, file.display()),
+                MusicBackend::Radio { name, url, .. } => write!(f, "{} ({})", name, url),
+            }
+        }
+    }
+
+    impl MusicSource for MusicBackend {
+        fn name(&self) -> &str {
+            match self {
+                MusicBackend::Jellyfin { name, .. } => name,
+                MusicBackend::Directory { name, .. } => name,
+                MusicBackend::Playlist { name, .. } => name,
+                MusicBackend::Radio { name, .. } => name,
+            }
+        }
+
+        async fn tracks(&self, ctx: &MusicContext) -> Result<Vec<Track>, MusicError> {
+            match self {
+                MusicBackend::Jellyfin { playlist_id, .. } => Ok(get_jellyfin_playlist(&ctx.jellyfin, playlist_id.as_deref())
+                    .await?
+                    .iter()
+                    .map(|title| Track::from_title(title, &ctx.jellyfin))
+                    .collect()),
+                MusicBackend::Directory { dir, .. } => directory_tracks(ctx, &ctx.root.join(dir)),
+                MusicBackend::Playlist { file, .. } => playlist_tracks(ctx, &ctx.root.join(file)),
+                MusicBackend::Radio { url, title, .. } => Ok(vec![radio_track(url, title)]),
+            }
+        }
+    }
+
+    /// The source named `name`, or the first one if there is no such source
+    pub fn select_source<'s>(sources: &'s [MusicBackend], name: Option<&str>) -> Option<&'s MusicBackend> {
+        name.and_then(|name| sources.iter().find(|s| s.name() == name))
+            .or_else(|| sources.first())
+    }
+
+    /// Every audio file directly inside `dir`
+    fn directory_tracks(ctx: &MusicContext, dir: &Path) -> Result<Vec<Track>, MusicError> {
+        let mut tracks = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && mime_type(&path).starts_with("audio/") {
+                tracks.extend(ctx.local_track(&path, None, None));
+            }
+        }
+        Ok(tracks)
+    }
+
+    /// Every playable entry of a playlist file, local entries are relative to it
+    fn playlist_tracks(ctx: &MusicContext, file: &Path) -> Result<Vec<Track>, MusicError> {
+        let content = fs::read_to_string(file)?;
+        let is_pls = file.extension().is_some_and(|e| e.eq_ignore_ascii_case("pls"));
+        let entries = if is_pls { parse_pls(&content) } else { parse_m3u(&content) };
+        let base = file.parent().unwrap_or(&ctx.root);
+
+        let tracks: Vec<Track> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                if entry.location.contains("://") {
+                    let mut track = radio_track(&entry.location, entry.title.as_deref().unwrap_or(&entry.location));
+                    if entry.duration.is_some() {
+                        track.duration = entry.duration;
+                        track.resume = Resume::Seek;
+                    }
+                    Some(track)
+                } else {
+                    ctx.local_track(&base.join(&entry.location), entry.title, entry.duration)
+                }
+            })
+            .collect();
+        Ok(tracks)
+    }
+
+    /// Sonos only plays plain HTTP radio streams with its own scheme
+    fn radio_track(url: &str, title: &str) -> Track {
+        let uri = match url.strip_prefix("http://") {
+            Some(rest) => format!("x-rincon-mp3radio://{}", rest),
+            None => url.to_string(),
+        };
+        Track {
+            id: url.to_string(),
+            title: title.to_string(),
+            artist: None,
+            album: None,
+            art: None,
+            duration: None,
+            uri,
+            mime: "audio/mpeg".to_string(),
+            resume: Resume::Live,
+            favourite: false,
+            play_count: 0,
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct PlaylistEntry {
+        location: String,
+        title: Option<String>,
+        duration: Option<u64>,
+    }
+
+    /// Parses plain and extended M3U, `#EXTINF:<seconds>,<title>` describes the next entry
+    fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+        let mut entries = Vec::new();
+        let mut info: Option<(Option<u64>, Option<String>)> = None;
+        for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                let (length, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+                let length = length.trim().parse::<i64>().ok().filter(|l| *l > 0).map(|l| l as u64);
+                let title = Some(title.trim().to_string()).filter(|t| !t.is_empty());
+                info = Some((length, title));
+            } else if !line.starts_with('#') {
+                let (duration, title) = info.take().unwrap_or_default();
+                entries.push(PlaylistEntry { location: line.to_string(), title, duration });
+            }
+        }
+        entries
+    }
+
+    /// Parses PLS playlists made of `FileN`, `TitleN` and `LengthN` keys
+    fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+        let mut entries: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+        for line in content.lines() {
+            let (key, value) = match line.trim().split_once('=') {
+                Some(val) => val,
+                None => continue,
+            };
+            let field = key.trim_end_matches(|c: char| c.is_ascii_digit());
+            let idx: u32 = match key[field.len()..].parse() {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            let entry = entries.entry(idx).or_default();
+            match field.to_lowercase().as_str() {
+                "file" => entry.location = value.to_string(),
+                "title" => entry.title = Some(value.to_string()),
+                "length" => entry.duration = value.parse::<i64>().ok().filter(|l| *l > 0).map(|l| l as u64),
+                _ => {}
+            }
+        }
+        entries.into_values().filter(|e| !e.location.is_empty()).collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::time_source::system_clock;
+
+        fn context(root: &Path) -> MusicContext {
+            MusicContext {
+                root: root.to_path_buf(),
+                base_url: "http://10.0.0.2:8321/".to_string(),
+                jellyfin: JellyfinConfig::default(),
+                selection: SelectionConfig::default(),
+                history: PlayHistory::default(),
+                min_duration: 0,
+                clock: system_clock(),
+            }
+        }
+
+        fn summary(entries: &[PlaylistEntry]) -> Vec<(&str, Option<&str>, Option<u64>)> {
+            entries
+                .iter()
+                .map(|e| (e.location.as_str(), e.title.as_deref(), e.duration))
+                .collect()
+        }
+
+        #[test]
+        fn parses_extended_m3u() {
+            let content = "#EXTM3U\n\
+                # Morning mix\n\
+                #EXTINF:215,Artist - Song\n\
+                songs/song.mp3\n\
+                \n\
+                #EXTINF:-1,Radio One\n\
+                http://radio.example/stream\n\
+                #EXTINF:60,\n\
+                ../other/plain.flac\n";
+            assert_eq!(
+                summary(&parse_m3u(content)),
+                vec![
+                    ("songs/song.mp3", Some("Artist - Song"), Some(215)),
+                    ("http://radio.example/stream", Some("Radio One"), None),
+                    ("../other/plain.flac", None, Some(60)),
+                ]
+            );
+        }
+
+        #[test]
+        fn parses_plain_m3u() {
+            let content = "one.mp3\r\n# not an entry\r\n  two.ogg  \r\n";
+            assert_eq!(summary(&parse_m3u(content)), vec![("one.mp3", None, None), ("two.ogg", None, None)]);
+        }
+
+        #[test]
+        fn parses_pls() {
+            let content = "[playlist]\n\
+                File1=one.mp3\n\
+                Title1=First\n\
+                Length1=120\n\
+                File10=ten.mp3\n\
+                File2=http://radio.example/stream\n\
+                Title2=Radio\n\
+                Length2=-1\n\
+                Title3=Without a file\n\
+                NumberOfEntries=4\n\
+                Version=2\n";
+            assert_eq!(
+                summary(&parse_pls(content)),
+                vec![
+                    ("one.mp3", Some("First"), Some(120)),
+                    ("http://radio.example/stream", Some("Radio"), None),
+                    ("ten.mp3", None, None),
+                ]
+            );
+        }
+
+        #[test]
+        fn resolves_playlist_entries_relative_to_the_playlist() {
+            let root = std::env::temp_dir().join(format!("freemind-sonos-playlist-{}", std::process::id()));
+            fs::create_dir_all(root.join("lists")).unwrap();
+            let file = root.join("lists/wake.m3u");
+            fs::write(
+                &file,
+                "#EXTINF:100,Song A\n../songs/a.mp3\nb b.mp3\n../../outside.mp3\nhttp://radio.example/live\n",
+            )
+            .unwrap();
+            let tracks = playlist_tracks(&context(&root), &file);
+            fs::remove_dir_all(&root).unwrap();
+
+            let tracks = tracks.unwrap();
+            let uris: Vec<&str> = tracks.iter().map(|t| t.uri.as_str()).collect();
+            assert_eq!(
+                uris,
+                vec![
+                    "http://10.0.0.2:8321/songs/a.mp3",
+                    "http://10.0.0.2:8321/lists/b%20b.mp3",
+                    "x-rincon-mp3radio://radio.example/live",
+                ]
+            );
+            assert_eq!(tracks[0].title, "Song A");
+            assert_eq!(tracks[0].duration, Some(100));
+            assert_eq!(tracks[1].title, "b b");
+            assert_eq!(tracks[2].resume, Resume::Live);
+        }
+
+        fn track(id: &str, duration: Option<u64>, favourite: bool, play_count: u32) -> Track {
+            Track {
+                id: id.to_string(),
+                title: id.to_string(),
+                artist: None,
+                album: None,
+                art: None,
+                duration,
+                uri: format!("http://10.0.0.2:8321/{}", id),
+                mime: "audio/mpeg".to_string(),
+                resume: Resume::Seek,
+                favourite,
+                play_count,
+            }
+        }
+
+        fn selection(strategy: Strategy, avoid_days: u16) -> SelectionConfig {
+            SelectionConfig {
+                strategy,
+                avoid_days,
+                favourite_weight: 3,
+                seed: None,
+            }
+        }
+
+        /// How often each track is picked in `rounds` picks
+        fn picks(tracks: &[Track], selection: &SelectionConfig, history: &PlayHistory, min_duration: u64, rounds: usize) -> BTreeMap<String, usize> {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut counts = BTreeMap::new();
+            for _ in 0..rounds {
+                let picked = select(tracks, selection, history, min_duration, NOW, &mut rng).unwrap();
+                *counts.entry(picked.id.clone()).or_insert(0) += 1;
+            }
+            counts
+        }
+
+        const NOW: u32 = 1_700_000_000;
+        const DAY: u32 = 24 * 60 * 60;
+
+        #[test]
+        fn weighs_favourites_and_play_counts() {
+            let tracks = vec![track("plain", None, false, 0), track("favourite", None, true, 0), track("often", None, false, 5)];
+            let counts = picks(&tracks, &selection(Strategy::Weighted, 0), &PlayHistory::default(), 0, 1000);
+            // Weights are 1, 3 and 6
+            assert!((50..150).contains(&counts["plain"]), "{:?}", counts);
+            assert!((230..370).contains(&counts["favourite"]), "{:?}", counts);
+            assert!((520..680).contains(&counts["often"]), "{:?}", counts);
+
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 0), &PlayHistory::default(), 0, 1000);
+            assert!(counts.values().all(|c| (250..420).contains(c)), "{:?}", counts);
+        }
+
+        #[test]
+        fn same_seed_picks_the_same() {
+            let tracks: Vec<Track> = (0..20).map(|i| track(&i.to_string(), None, false, 0)).collect();
+            let mut config = selection(Strategy::Uniform, 0);
+            config.seed = Some(42);
+            let history = PlayHistory::default();
+            let first = select(&tracks, &config, &history, 0, NOW, &mut config.rng()).unwrap();
+            let second = select(&tracks, &config, &history, 0, NOW, &mut config.rng()).unwrap();
+            assert_eq!(first.id, second.id);
+        }
+
+        #[test]
+        fn avoids_recently_played_tracks() {
+            let tracks = vec![track("yesterday", None, false, 0), track("last week", None, false, 0), track("never", None, false, 0)];
+            let mut history = PlayHistory::default();
+            history.records_mut().record("yesterday", NOW - DAY);
+            history.records_mut().record("last week", NOW - 7 * DAY);
+
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 3), &history, 0, 200);
+            assert!(!counts.contains_key("yesterday"), "{:?}", counts);
+            assert!(counts.contains_key("last week") && counts.contains_key("never"), "{:?}", counts);
+
+            // Without a window recent tracks are fine again
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 0), &history, 0, 200);
+            assert!(counts.contains_key("yesterday"), "{:?}", counts);
+        }
+
+        #[test]
+        fn repeats_when_everything_was_played_recently() {
+            let tracks = vec![track("a", None, false, 0), track("b", None, false, 0)];
+            let mut history = PlayHistory::default();
+            history.records_mut().record("a", NOW - DAY);
+            history.records_mut().record("b", NOW - 2 * DAY);
+
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 3), &history, 0, 200);
+            assert_eq!(counts.len(), 2, "{:?}", counts);
+        }
+
+        #[test]
+        fn prefers_tracks_longer_than_the_fade() {
+            let tracks = vec![track("short", Some(20), false, 0), track("long", Some(240), false, 0), track("stream", None, false, 0)];
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 0), &PlayHistory::default(), 30, 200);
+            assert!(!counts.contains_key("short"), "{:?}", counts);
+            assert_eq!(counts.len(), 2, "{:?}", counts);
+        }
+
+        #[test]
+        fn falls_back_to_short_tracks() {
+            let tracks = vec![track("short", Some(20), false, 0), track("shorter", Some(10), false, 0)];
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 0), &PlayHistory::default(), 30, 200);
+            assert_eq!(counts.len(), 2, "{:?}", counts);
+
+            // Being long enough counts more than not being played recently
+            let tracks = vec![track("short", Some(20), false, 0), track("long", Some(240), false, 0)];
+            let mut history = PlayHistory::default();
+            history.records_mut().record("long", NOW - DAY);
+            let counts = picks(&tracks, &selection(Strategy::Uniform, 3), &history, 30, 200);
+            assert_eq!(counts.keys().collect::<Vec<_>>(), vec!["long"]);
+        }
+
+        #[test]
+        fn picks_different_tracks() {
+            let ctx = context(Path::new("/srv/media"));
+            let tracks: Vec<Track> = ["a", "b", "c", "d"].iter().map(|id| track(id, None, false, 0)).collect();
+            let mut picked: Vec<String> = ctx.select(tracks.clone(), 3).into_iter().map(|t| t.id).collect();
+            picked.sort();
+            picked.dedup();
+            assert_eq!(picked.len(), 3);
+            assert_eq!(ctx.select(tracks, 10).len(), 4);
+            assert!(ctx.select(Vec::new(), 2).is_empty());
+        }
+
+        #[test]
+        fn nothing_to_pick_from() {
+            let mut rng = StdRng::seed_from_u64(1);
+            assert!(select(&[], &selection(Strategy::Weighted, 3), &PlayHistory::default(), 0, NOW, &mut rng).is_none());
+        }
+
+        #[test]
+        fn local_tracks_are_keyed_by_their_path() {
+            let ctx = context(Path::new("/srv/media"));
+            let track = ctx.local_track(Path::new("/srv/media/music/./My Song.mp3"), None, None).unwrap();
+            assert_eq!(track.id, "music/My Song.mp3");
+            assert_eq!(track.uri, "http://10.0.0.2:8321/music/My%20Song.mp3");
+            assert!(ctx.local_track(Path::new("/srv/other/song.mp3"), None, None).is_none());
+        }
+
+        #[test]
+        fn encodes_file_urls() {
+            let ctx = context(Path::new("/srv/media"));
+            assert_eq!(ctx.file_url("tts.mp3"), "http://10.0.0.2:8321/tts.mp3");
+            assert_eq!(
+                ctx.file_url("sounds/Ding #2 & more?.mp3"),
+                "http://10.0.0.2:8321/sounds/Ding%20%232%20%26%20more%3F.mp3"
+            );
+        }
+
+        #[test]
+        fn builds_jellyfin_stream_urls() {
+            let config = JellyfinConfig {
+                server: "https://jf.local/".to_string(),
+                api_key: "k/y+1".to_string(),
+                container: "aac".to_string(),
+                bitrate: 256,
+                ..JellyfinConfig::default()
+            };
+            let title: Title = serde_json::from_str(
+                r#"{"Name":"Song","Id":"a b/c","AlbumArtist":"Band","Album":null,"RunTimeTicks":2150000000}"#,
+            )
+            .unwrap();
+            let track = Track::from_title(&title, &config);
+            assert_eq!(
+                track.uri,
+                "https://jf.local/Audio/a%20b%2Fc/stream.aac?api_key=k%2Fy%2B1&audioBitRate=256000"
+            );
+            assert_eq!(track.art.as_deref(), Some("https://jf.local/Items/a%20b%2Fc/Images/Primary?api_key=k%2Fy%2B1"));
+            assert_eq!(track.duration, Some(215));
+            assert_eq!(track.mime, "audio/aac");
+            assert_eq!(track.uri_at(0), track.uri);
+            assert_eq!(track.uri_at(90), format!("{}&startTimeTicks=900000000", track.uri));
+            assert_eq!(
+                config.playlist_url("list/1"),
+                "https://jf.local/Playlists/list%2F1/Items?api_key=k%2Fy%2B1&userId=id%20of%20the%20user"
+            );
+
+            let ogg = JellyfinConfig {
+                container: "ogg".to_string(),
+                codec: Some("vorbis".to_string()),
+                ..config
+            };
+            assert_eq!(
+                Track::from_title(&title, &ogg).uri,
+                "https://jf.local/Audio/a%20b%2Fc/stream.ogg?api_key=k%2Fy%2B1&audioBitRate=256000&audioCodec=vorbis"
+            );
+        }
+
+        #[test]
+        fn jellyfin_tracks_without_length_have_no_duration() {
+            let title: Title = serde_json::from_str(r#"{"Name":"Song","Id":"a","AlbumArtist":"Band","Album":null}"#).unwrap();
+            assert_eq!(Track::from_title(&title, &JellyfinConfig::default()).duration, None);
+        }
+
+        #[test]
+        fn jellyfin_config_fills_in_new_fields() {
+            let old = r#"{"server":"https://jf.local/","api_key":"key","playlist_id":"list","user_id":"user"}"#;
+            let config: JellyfinConfig = serde_json::from_str(old).unwrap();
+            assert_eq!(config.playlist_id, "list");
+            assert_eq!(config.container, "mp3");
+            assert_eq!(config.codec, None);
+            assert_eq!(config.bitrate, 192);
         }
     }
 }
@@ -0,0 +1,187 @@
+pub mod didl_lite {
+    use crate::speaker::box_controller::xml_escape;
+
+    pub const MUSIC_TRACK: &str = "object.item.audioItem.musicTrack";
+    pub const AUDIO_ITEM: &str = "object.item.audioItem";
+    pub const AUDIO_BROADCAST: &str = "object.item.audioItem.audioBroadcast";
+
+    /// Builds the DIDL-Lite document describing a single item, as expected in
+    /// `CurrentURIMetaData` and `EnqueuedURIMetaData`
+    #[derive(Debug, Clone)]
+    pub struct DidlItem {
+        uri: String,
+        title: String,
+        class: &'static str,
+        mime: String,
+        artist: Option<String>,
+        album: Option<String>,
+        album_art: Option<String>,
+        description: Option<String>,
+        /// Length in seconds
+        duration: Option<u64>,
+    }
+
+    impl DidlItem {
+        pub fn new(uri: &str, title: &str) -> Self {
+            Self {
+                uri: uri.to_string(),
+                title: title.to_string(),
+                class: MUSIC_TRACK,
+                mime: "audio/mpeg".to_string(),
+                artist: None,
+                album: None,
+                album_art: None,
+                description: None,
+                duration: None,
+            }
+        }
+
+        pub fn class(mut self, class: &'static str) -> Self {
+            self.class = class;
+            self
+        }
+
+        pub fn mime(mut self, mime: &str) -> Self {
+            self.mime = mime.to_string();
+            self
+        }
+
+        pub fn artist(mut self, artist: Option<&str>) -> Self {
+            self.artist = artist.map(|a| a.to_string());
+            self
+        }
+
+        pub fn album(mut self, album: Option<&str>) -> Self {
+            self.album = album.map(|a| a.to_string());
+            self
+        }
+
+        pub fn album_art(mut self, url: Option<&str>) -> Self {
+            self.album_art = url.map(|u| u.to_string());
+            self
+        }
+
+        pub fn description(mut self, description: Option<&str>) -> Self {
+            self.description = description.map(|d| d.to_string());
+            self
+        }
+
+        pub fn duration(mut self, seconds: Option<u64>) -> Self {
+            self.duration = seconds.filter(|s| *s > 0);
+            self
+        }
+
+        /// The DIDL-Lite document, still to be escaped when used as an action argument
+        pub fn build(&self) -> String {
+            let mut item = format!("<dc:title>{}</dc:title>", xml_escape(&self.title));
+            if let Some(artist) = &self.artist {
+                item.push_str(&format!("<dc:creator>{}</dc:creator>", xml_escape(artist)));
+                item.push_str(&format!("<upnp:artist>{}</upnp:artist>", xml_escape(artist)));
+            }
+            if let Some(album) = &self.album {
+                item.push_str(&format!("<upnp:album>{}</upnp:album>", xml_escape(album)));
+            }
+            if let Some(art) = &self.album_art {
+                item.push_str(&format!("<upnp:albumArtURI>{}</upnp:albumArtURI>", xml_escape(art)));
+            }
+            if let Some(description) = &self.description {
+                item.push_str(&format!("<dc:description>{}</dc:description>", xml_escape(description)));
+            }
+            item.push_str(&format!("<upnp:class>{}</upnp:class>", self.class));
+
+            let duration = match self.duration {
+                Some(s) => format!(r#" duration="{}:{:02}:{:02}""#, s / 3600, s / 60 % 60, s % 60),
+                None => String::new(),
+            };
+            item.push_str(&format!(
+                r#"<res protocolInfo="http-get:*:{}:*"{}>{}</res>"#,
+                xml_escape(&self.mime),
+                duration,
+                xml_escape(&self.uri)
+            ));
+
+            format!(
+                concat!(
+                    r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" "#,
+                    r#"xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" "#,
+                    r#"xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/" "#,
+                    r#"xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/">"#,
+                    r#"<item id="-1" parentID="-1" restricted="true">{}</item></DIDL-Lite>"#,
+                ),
+                item
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// The item inside the DIDL-Lite envelope
+        fn item(didl: &str) -> &str {
+            let start = didl.find("restricted=\"true\">").unwrap() + "restricted=\"true\">".len();
+            &didl[start..didl.len() - "</item></DIDL-Lite>".len()]
+        }
+
+        #[test]
+        fn escapes_text() {
+            let didl = DidlItem::new("http://10.0.0.2:8321/a.mp3?x=1&y=2", "Rock & <Roll>")
+                .artist(Some("Simon & Garfunkel"))
+                .album(Some("\"Live\""))
+                .build();
+            assert_eq!(
+                item(&didl),
+                "<dc:title>Rock &amp; &lt;Roll&gt;</dc:title>\
+                 <dc:creator>Simon &amp; Garfunkel</dc:creator><upnp:artist>Simon &amp; Garfunkel</upnp:artist>\
+                 <upnp:album>&quot;Live&quot;</upnp:album>\
+                 <upnp:class>object.item.audioItem.musicTrack</upnp:class>\
+                 <res protocolInfo=\"http-get:*:audio/mpeg:*\">http://10.0.0.2:8321/a.mp3?x=1&amp;y=2</res>"
+            );
+        }
+
+        #[test]
+        fn formats_the_duration() {
+            let res = |seconds: Option<u64>| {
+                let didl = DidlItem::new("http://a/b.flac", "B").mime("audio/flac").duration(seconds).build();
+                item(&didl).split_once("</upnp:class>").unwrap().1.to_string()
+            };
+            assert_eq!(res(Some(215)), "<res protocolInfo=\"http-get:*:audio/flac:*\" duration=\"0:03:35\">http://a/b.flac</res>");
+            assert_eq!(res(Some(3 * 3600 + 5)), "<res protocolInfo=\"http-get:*:audio/flac:*\" duration=\"3:00:05\">http://a/b.flac</res>");
+            assert_eq!(res(Some(0)), "<res protocolInfo=\"http-get:*:audio/flac:*\">http://a/b.flac</res>");
+            assert_eq!(res(None), "<res protocolInfo=\"http-get:*:audio/flac:*\">http://a/b.flac</res>");
+        }
+
+        #[test]
+        fn leaves_out_missing_fields() {
+            let didl = DidlItem::new("http://a/tts.mp3", "Briefing")
+                .class(AUDIO_ITEM)
+                .artist(None)
+                .album(None)
+                .album_art(None)
+                .description(None)
+                .build();
+            assert_eq!(
+                item(&didl),
+                "<dc:title>Briefing</dc:title><upnp:class>object.item.audioItem</upnp:class>\
+                 <res protocolInfo=\"http-get:*:audio/mpeg:*\">http://a/tts.mp3</res>"
+            );
+            assert!(didl.starts_with("<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" "));
+        }
+
+        #[test]
+        fn describes_broadcasts() {
+            let didl = DidlItem::new("x-rincon-mp3radio://radio.example/live", "Radio")
+                .class(AUDIO_BROADCAST)
+                .album_art(Some("http://radio.example/logo.png"))
+                .description(Some("Morning show"))
+                .build();
+            assert_eq!(
+                item(&didl),
+                "<dc:title>Radio</dc:title><upnp:albumArtURI>http://radio.example/logo.png</upnp:albumArtURI>\
+                 <dc:description>Morning show</dc:description>\
+                 <upnp:class>object.item.audioItem.audioBroadcast</upnp:class>\
+                 <res protocolInfo=\"http-get:*:audio/mpeg:*\">x-rincon-mp3radio://radio.example/live</res>"
+            );
+        }
+    }
+}
@@ -0,0 +1,118 @@
+pub mod app_error {
+    use crate::content::music::MusicError;
+    use crate::content::speech::TtsError;
+    use crate::freemind::freemind_handler::FreemindError;
+    use std::fmt;
+
+    /// Everything that can make a run fail, grouped by what the user has to fix.
+    /// Each kind exits the program with its own code.
+    #[derive(Debug)]
+    pub enum AppError {
+        /// The configuration or the media directory is unusable
+        Config(String),
+        /// A server or the local network could not be reached
+        Network(String),
+        /// A server rejected the credentials
+        Auth(String),
+        /// A server answered with something that could not be understood
+        Parse(String),
+        /// A speaker could not be found or refused a command
+        Speaker(String),
+        /// No speech could be generated
+        Speech(String),
+        /// The requested element does not exist
+        NotFound(String),
+    }
+
+    impl AppError {
+        pub fn exit_code(&self) -> i32 {
+            match self {
+                AppError::Config(_) => 2,
+                AppError::Network(_) => 3,
+                AppError::Auth(_) => 4,
+                AppError::Parse(_) => 5,
+                AppError::Speaker(_) => 6,
+                AppError::Speech(_) => 7,
+                AppError::NotFound(_) => 8,
+            }
+        }
+
+        /// Failures that won't go away by trying again later
+        pub fn is_permanent(&self) -> bool {
+            matches!(self, AppError::Config(_) | AppError::Auth(_))
+        }
+    }
+
+    impl fmt::Display for AppError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AppError::Config(msg) => write!(f, "Configuration error: {}", msg),
+                AppError::Network(msg) => write!(f, "Network error: {}", msg),
+                AppError::Auth(msg) => write!(f, "Authentication failed: {}", msg),
+                AppError::Parse(msg) => write!(f, "Unexpected response: {}", msg),
+                AppError::Speaker(msg) => write!(f, "Speaker error: {}", msg),
+                AppError::Speech(msg) => write!(f, "Speech error: {}", msg),
+                AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            }
+        }
+    }
+
+    impl From<FreemindError> for AppError {
+        fn from(err: FreemindError) -> Self {
+            match err {
+                FreemindError::Http(_) => AppError::Network(err.to_string()),
+                FreemindError::Auth(_) => AppError::Auth(err.to_string()),
+                FreemindError::Xml(_) => AppError::Parse(err.to_string()),
+                FreemindError::NotFound(_) | FreemindError::MissingId => AppError::NotFound(err.to_string()),
+            }
+        }
+    }
+
+    impl From<TtsError> for AppError {
+        fn from(err: TtsError) -> Self {
+            match err {
+                TtsError::Http(_) => AppError::Network(err.to_string()),
+                _ => AppError::Speech(err.to_string()),
+            }
+        }
+    }
+
+    impl From<MusicError> for AppError {
+        fn from(err: MusicError) -> Self {
+            match err {
+                MusicError::Http(_) => AppError::Network(err.to_string()),
+                MusicError::Io(_) => AppError::Config(err.to_string()),
+            }
+        }
+    }
+
+    impl From<sonor::Error> for AppError {
+        fn from(err: sonor::Error) -> Self {
+            AppError::Speaker(format!("{:?}", err))
+        }
+    }
+
+    impl From<handlebars::RenderError> for AppError {
+        fn from(err: handlebars::RenderError) -> Self {
+            AppError::Config(format!("Failed to render a template: {}", err))
+        }
+    }
+
+    impl From<Box<handlebars::TemplateError>> for AppError {
+        fn from(err: Box<handlebars::TemplateError>) -> Self {
+            AppError::Config(format!("Failed to load a template: {}", err))
+        }
+    }
+
+    impl From<confy::ConfyError> for AppError {
+        fn from(err: confy::ConfyError) -> Self {
+            AppError::Config(err.to_string())
+        }
+    }
+
+    impl From<hyper::Error> for AppError {
+        fn from(err: hyper::Error) -> Self {
+            AppError::Network(err.to_string())
+        }
+    }
+}
@@ -0,0 +1,546 @@
+pub mod event_listener {
+    use futures_util::stream::{self, BoxStream, StreamExt};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use reqwest::{Client, Method};
+    use serde::{Deserialize, Serialize};
+    use sonor::Speaker;
+    use std::collections::{HashMap, HashSet};
+    use std::convert::Infallible;
+    use std::fmt;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+    use tokio::task::JoinHandle;
+
+    const AV_TRANSPORT_EVENTS: &str = "/MediaRenderer/AVTransport/Event";
+    const RENDERING_CONTROL_EVENTS: &str = "/MediaRenderer/RenderingControl/Event";
+    /// Seconds a subscription lasts, it is renewed after half of it
+    const SUBSCRIPTION_TIMEOUT: u32 = 300;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct EventConfig {
+        enabled: bool,
+        port: u16,
+    }
+
+    impl ::std::default::Default for EventConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                port: 8322,
+            }
+        }
+    }
+
+    impl fmt::Display for EventConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
+
+    impl EventConfig {
+        pub fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        pub fn port(&self) -> u16 {
+            self.port
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum EventService {
+        AvTransport,
+        RenderingControl,
+    }
+
+    impl EventService {
+        fn path(&self) -> &'static str {
+            match self {
+                EventService::AvTransport => AV_TRANSPORT_EVENTS,
+                EventService::RenderingControl => RENDERING_CONTROL_EVENTS,
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            match self {
+                EventService::AvTransport => "AVTransport",
+                EventService::RenderingControl => "RenderingControl",
+            }
+        }
+
+        fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "AVTransport" => Some(EventService::AvTransport),
+                "RenderingControl" => Some(EventService::RenderingControl),
+                _ => None,
+            }
+        }
+    }
+
+    /// A change reported by a speaker, `values` holds every state variable
+    /// included in the notification
+    #[derive(Debug, Clone)]
+    pub struct SpeakerEvent {
+        /// Host of the speaker that sent the event
+        pub speaker: String,
+        pub service: EventService,
+        pub values: HashMap<String, String>,
+    }
+
+    impl SpeakerEvent {
+        pub fn transport_state(&self) -> Option<&str> {
+            self.values.get("TransportState").map(|s| s.as_str())
+        }
+
+        /// Playback stopped or was paused
+        pub fn is_stopped(&self) -> bool {
+            matches!(self.transport_state(), Some("STOPPED") | Some("PAUSED_PLAYBACK"))
+        }
+
+        /// Playback was paused, which only happens when someone presses pause
+        pub fn is_paused(&self) -> bool {
+            self.transport_state() == Some("PAUSED_PLAYBACK")
+        }
+
+        pub fn track_uri(&self) -> Option<&str> {
+            self.values.get("CurrentTrackURI").map(|s| s.as_str())
+        }
+
+        pub fn volume(&self) -> Option<u16> {
+            self.values.get("Volume").and_then(|v| v.parse().ok())
+        }
+
+        /// The transport state or the track changed. Volume changes, including the
+        /// ones made while fading, are not.
+        pub fn is_transport_change(&self) -> bool {
+            self.service == EventService::AvTransport && (self.transport_state().is_some() || self.track_uri().is_some())
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum SubscriptionError {
+        Http(reqwest::Error),
+        /// The speaker accepted the subscription without naming it
+        MissingSid(String),
+    }
+
+    impl fmt::Display for SubscriptionError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SubscriptionError::Http(err) => write!(f, "Subscription request failed: {}", err),
+                SubscriptionError::MissingSid(endpoint) => write!(f, "{} did not return a subscription id", endpoint),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for SubscriptionError {
+        fn from(err: reqwest::Error) -> Self {
+            SubscriptionError::Http(err)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Subscriptions {
+        /// The subscriptions currently held, by SID with the endpoint they belong to
+        held: HashMap<String, String>,
+        /// Callback paths of SUBSCRIBE requests waiting for their response. The
+        /// speaker sends the initial NOTIFY right away, often before the response
+        /// tells us the SID.
+        pending: HashSet<String>,
+    }
+
+    impl Subscriptions {
+        /// Whether a NOTIFY with `sid` on `path` belongs to one of our subscriptions
+        fn accepts(&self, sid: Option<&str>, path: &str) -> bool {
+            sid.is_some_and(|sid| self.held.contains_key(sid)) || self.pending.contains(path.trim_matches('/'))
+        }
+    }
+
+    type SharedSubscriptions = Arc<Mutex<Subscriptions>>;
+
+    /// Receives UPnP (GENA) event notifications of the speakers on a local HTTP
+    /// endpoint and hands them out as streams.
+    #[derive(Debug, Clone)]
+    pub struct EventListener {
+        addr: SocketAddr,
+        sender: broadcast::Sender<SpeakerEvent>,
+        client: Client,
+        subscriptions: SharedSubscriptions,
+        /// Tasks keeping the subscriptions alive
+        renewals: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl EventListener {
+        /// Binds the callback endpoint and serves notifications in a background task
+        pub fn spawn(addr: SocketAddr) -> Result<Self, hyper::Error> {
+            let (sender, _) = broadcast::channel(64);
+            let subscriptions = SharedSubscriptions::default();
+            let tx = sender.clone();
+            let known = subscriptions.clone();
+            let make_svc = make_service_fn(move |_conn| {
+                let tx = tx.clone();
+                let known = known.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| handle(tx.clone(), known.clone(), req))) }
+            });
+
+            let server = Server::try_bind(&addr)?.serve(make_svc);
+            let addr = server.local_addr();
+            log::info!("Listening for speaker events on {}", addr);
+            tokio::spawn(async move {
+                if let Err(err) = server.await {
+                    log::error!("Event listener stopped: {}", err);
+                }
+            });
+
+            Ok(Self {
+                addr,
+                sender,
+                client: Client::new(),
+                subscriptions,
+                renewals: Default::default(),
+            })
+        }
+
+        /// Every event received from now on
+        pub fn events(&self) -> BoxStream<'static, SpeakerEvent> {
+            stream::unfold(self.sender.subscribe(), |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) => return Some((event, rx)),
+                        Err(broadcast::error::RecvError::Lagged(n)) => log::debug!("Skipped {} speaker events", n),
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            })
+            .boxed()
+        }
+
+        /// Subscribes to transport and volume changes of `spk` and keeps the
+        /// subscriptions alive in the background until `unsubscribe_all`
+        pub async fn subscribe(&self, spk: &Speaker) -> Result<(), SubscriptionError> {
+            let url = spk.device().url();
+            let base = format!("http://{}", url.authority().map(|a| a.as_str()).unwrap_or_default());
+            self.subscribe_at(&base, url.host().unwrap_or_default()).await
+        }
+
+        /// Subscribes to the services of the speaker at `base`, its events are
+        /// reported as coming from `host`
+        async fn subscribe_at(&self, base: &str, host: &str) -> Result<(), SubscriptionError> {
+            for service in [EventService::AvTransport, EventService::RenderingControl] {
+                let endpoint = format!("{}{}", base, service.path());
+                let callback = format!("{}/{}", host, service.name());
+                let sid = self.request_subscription(&endpoint, Some(&callback), None).await?;
+                log::debug!("Subscribed to {} of {} as {}", service.name(), host, sid);
+                self.track(None, &sid, &endpoint);
+
+                let listener = self.clone();
+                let renewal = tokio::spawn(async move {
+                    let mut sid = sid;
+                    loop {
+                        tokio::time::sleep(Duration::from_secs(SUBSCRIPTION_TIMEOUT as u64 / 2)).await;
+                        let renewed = match listener.request_subscription(&endpoint, None, Some(&sid)).await {
+                            Ok(val) => Ok(val),
+                            Err(err) => {
+                                log::debug!("Renewing {} failed, subscribing again: {}", sid, err);
+                                listener.request_subscription(&endpoint, Some(&callback), None).await
+                            }
+                        };
+                        match renewed {
+                            Ok(val) => {
+                                listener.track(Some(&sid), &val, &endpoint);
+                                sid = val;
+                            }
+                            Err(err) => log::error!("Lost the event subscription at {}: {}", endpoint, err),
+                        }
+                    }
+                });
+                self.renewals.lock().unwrap().push(renewal);
+            }
+            Ok(())
+        }
+
+        /// Records `sid` as held for `endpoint`, replacing `old`
+        fn track(&self, old: Option<&str>, sid: &str, endpoint: &str) {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            if let Some(old) = old.filter(|old| *old != sid) {
+                log::debug!("Subscription {} was replaced by {}", old, sid);
+                subscriptions.held.remove(old);
+            }
+            subscriptions.held.insert(sid.to_string(), endpoint.to_string());
+        }
+
+        /// Stops renewing and cancels every subscription, so the speakers stop
+        /// sending events to an endpoint that is going away
+        pub async fn unsubscribe_all(&self) {
+            self.renewals.lock().unwrap().drain(..).for_each(|renewal| renewal.abort());
+            let subscriptions: Vec<(String, String)> = self.subscriptions.lock().unwrap().held.drain().collect();
+            for (sid, endpoint) in subscriptions {
+                let result = self
+                    .client
+                    .request(Method::from_bytes(b"UNSUBSCRIBE").unwrap(), &endpoint)
+                    .header("SID", &sid)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status());
+                match result {
+                    Ok(_) => log::debug!("Unsubscribed {} at {}", sid, endpoint),
+                    Err(err) => log::debug!("Failed to unsubscribe {} at {}: {}", sid, endpoint, err),
+                }
+            }
+        }
+
+        /// Sends a SUBSCRIBE request, either a new one with the path of a callback
+        /// on this listener or a renewal of `sid`. Returns the subscription id.
+        async fn request_subscription(&self, endpoint: &str, callback: Option<&str>, sid: Option<&str>) -> Result<String, SubscriptionError> {
+            let mut req = self
+                .client
+                .request(Method::from_bytes(b"SUBSCRIBE").unwrap(), endpoint)
+                .header("TIMEOUT", format!("Second-{}", SUBSCRIPTION_TIMEOUT));
+            if let Some(path) = callback {
+                req = req
+                    .header("CALLBACK", format!("<http://{}/{}>", self.addr, path))
+                    .header("NT", "upnp:event");
+            }
+            if let Some(sid) = sid {
+                req = req.header("SID", sid);
+            }
+
+            if let Some(path) = callback {
+                self.subscriptions.lock().unwrap().pending.insert(path.to_string());
+            }
+            let res = req.send().await;
+            if let Some(path) = callback {
+                self.subscriptions.lock().unwrap().pending.remove(path);
+            }
+            let res = res?.error_for_status()?;
+            res.headers()
+                .get("SID")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+                .or_else(|| sid.map(|s| s.to_string()))
+                .ok_or_else(|| SubscriptionError::MissingSid(endpoint.to_string()))
+        }
+    }
+
+    async fn handle(tx: broadcast::Sender<SpeakerEvent>, subscriptions: SharedSubscriptions, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let mut res = Response::new(Body::empty());
+        if req.method().as_str() != "NOTIFY" {
+            *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            return Ok(res);
+        }
+        // Events of subscriptions we don't hold (anymore) are refused
+        let sid = req.headers().get("SID").and_then(|v| v.to_str().ok());
+        let known = subscriptions.lock().unwrap().accepts(sid, req.uri().path());
+        if !known {
+            *res.status_mut() = StatusCode::PRECONDITION_FAILED;
+            return Ok(res);
+        }
+
+        let mut parts = req.uri().path().trim_matches('/').splitn(2, '/');
+        let speaker = parts.next().unwrap_or_default().to_string();
+        let service = match parts.next().and_then(EventService::from_name) {
+            Some(val) => val,
+            None => {
+                *res.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(res);
+            }
+        };
+
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(val) => String::from_utf8_lossy(&val).to_string(),
+            Err(_) => {
+                *res.status_mut() = StatusCode::BAD_REQUEST;
+                return Ok(res);
+            }
+        };
+
+        let values = parse_notification(&body);
+        log::debug!("{} event from {}: {:?}", service.name(), speaker, values);
+        // Nobody listening is fine, the event is simply dropped
+        tx.send(SpeakerEvent { speaker, service, values }).unwrap_or(0);
+        Ok(res)
+    }
+
+    /// Extracts the state variables of a NOTIFY body. `LastChange` is expanded
+    /// into the variables it contains, volumes are only taken for the master channel.
+    pub fn parse_notification(body: &str) -> HashMap<String, String> {
+        let mut values = HashMap::new();
+        let mut reader = Reader::from_str(body);
+        reader.trim_text(true);
+        let mut current: Option<String> = None;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(e)) => {
+                    let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                    if name != "propertyset" && name != "property" {
+                        current = Some(name);
+                    }
+                }
+                Ok(Event::Text(t)) => {
+                    if let (Some(name), Ok(text)) = (current.take(), t.unescape()) {
+                        if name == "LastChange" {
+                            values.extend(parse_last_change(&text));
+                        } else {
+                            values.insert(name, text.to_string());
+                        }
+                    }
+                }
+                Ok(Event::End(_)) => current = None,
+                Ok(Event::Eof) | Err(_) => break,
+                _ => {}
+            }
+        }
+        values
+    }
+
+    fn parse_last_change(xml: &str) -> HashMap<String, String> {
+        let mut values = HashMap::new();
+        let mut reader = Reader::from_str(xml);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                    let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                    let mut val = None;
+                    let mut channel = None;
+                    for attr in e.attributes().flatten() {
+                        match attr.key.local_name().as_ref() {
+                            b"val" => val = attr.unescape_value().ok().map(|v| v.to_string()),
+                            b"channel" => channel = attr.unescape_value().ok().map(|v| v.to_string()),
+                            _ => {}
+                        }
+                    }
+                    if let Some(val) = val {
+                        if channel.is_none() || channel.as_deref() == Some("Master") {
+                            values.insert(name, val);
+                        }
+                    }
+                }
+                Ok(Event::Eof) | Err(_) => break,
+                _ => {}
+            }
+        }
+        values
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const AV_TRANSPORT_NOTIFY: &str = r#"<?xml version="1.0"?>
+<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>&lt;Event xmlns=&quot;urn:schemas-upnp-org:metadata-1-0/AVT/&quot;&gt;&lt;InstanceID val=&quot;0&quot;&gt;&lt;TransportState val=&quot;PLAYING&quot;/&gt;&lt;CurrentTrackURI val=&quot;http://10.0.0.2:8321/tts.mp3?a=1&amp;amp;b=2&quot;/&gt;&lt;/InstanceID&gt;&lt;/Event&gt;</LastChange></e:property></e:propertyset>"#;
+
+        const RENDERING_CONTROL_NOTIFY: &str = r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>&lt;Event xmlns=&quot;urn:schemas-upnp-org:metadata-1-0/RCS/&quot;&gt;&lt;InstanceID val=&quot;0&quot;&gt;&lt;Volume channel=&quot;Master&quot; val=&quot;12&quot;/&gt;&lt;Volume channel=&quot;LF&quot; val=&quot;100&quot;/&gt;&lt;Mute channel=&quot;Master&quot; val=&quot;0&quot;/&gt;&lt;/InstanceID&gt;&lt;/Event&gt;</LastChange></e:property></e:propertyset>"#;
+
+        fn event(service: EventService, body: &str) -> SpeakerEvent {
+            SpeakerEvent {
+                speaker: "10.0.0.5".to_string(),
+                service,
+                values: parse_notification(body),
+            }
+        }
+
+        #[test]
+        fn parses_last_change() {
+            let values = parse_notification(AV_TRANSPORT_NOTIFY);
+            assert_eq!(values.get("TransportState").map(String::as_str), Some("PLAYING"));
+            assert_eq!(
+                values.get("CurrentTrackURI").map(String::as_str),
+                Some("http://10.0.0.2:8321/tts.mp3?a=1&b=2")
+            );
+        }
+
+        #[test]
+        fn takes_only_the_master_volume() {
+            let values = parse_notification(RENDERING_CONTROL_NOTIFY);
+            assert_eq!(values.get("Volume").map(String::as_str), Some("12"));
+            assert_eq!(values.get("Mute").map(String::as_str), Some("0"));
+        }
+
+        #[test]
+        fn parses_plain_properties() {
+            let body = r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><ZoneGroupName>Bedroom</ZoneGroupName></e:property><e:property><ZoneName>Bedroom</ZoneName></e:property></e:propertyset>"#;
+            let values = parse_notification(body);
+            assert_eq!(values.get("ZoneGroupName").map(String::as_str), Some("Bedroom"));
+            assert_eq!(values.get("ZoneName").map(String::as_str), Some("Bedroom"));
+            assert!(parse_notification("not xml at all").is_empty());
+        }
+
+        #[test]
+        fn only_transport_changes_count() {
+            let transport = event(EventService::AvTransport, AV_TRANSPORT_NOTIFY);
+            assert!(transport.is_transport_change());
+            assert!(!transport.is_stopped());
+            assert!(!event(EventService::RenderingControl, RENDERING_CONTROL_NOTIFY).is_transport_change());
+            assert!(!event(EventService::AvTransport, RENDERING_CONTROL_NOTIFY).is_transport_change());
+        }
+
+        /// A speaker that sends the initial NOTIFY of a subscription before
+        /// answering the SUBSCRIBE, and reports the status the NOTIFY got
+        async fn notifying_speaker(statuses: tokio::sync::mpsc::UnboundedSender<u16>) -> SocketAddr {
+            let make_svc = make_service_fn(move |_conn| {
+                let statuses = statuses.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let statuses = statuses.clone();
+                        async move {
+                            let mut res = Response::new(Body::empty());
+                            let callback = req.headers().get("CALLBACK").and_then(|v| v.to_str().ok());
+                            if let Some(callback) = callback.map(|c| c.trim_matches(|c| c == '<' || c == '>').to_string()) {
+                                let body = if callback.ends_with("AVTransport") { AV_TRANSPORT_NOTIFY } else { RENDERING_CONTROL_NOTIFY };
+                                let notified = Client::new()
+                                    .request(Method::from_bytes(b"NOTIFY").unwrap(), &callback)
+                                    .header("SID", format!("uuid:{}", req.uri().path()))
+                                    .body(body)
+                                    .send()
+                                    .await
+                                    .unwrap();
+                                statuses.send(notified.status().as_u16()).unwrap();
+                                res.headers_mut().insert("SID", format!("uuid:{}", req.uri().path()).parse().unwrap());
+                            }
+                            Ok::<_, Infallible>(res)
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            addr
+        }
+
+        #[tokio::test]
+        async fn takes_notifications_sent_before_the_subscription_is_confirmed() {
+            let (tx, mut statuses) = tokio::sync::mpsc::unbounded_channel();
+            let speaker = notifying_speaker(tx).await;
+            let listener = EventListener::spawn(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+            let mut events = listener.events();
+
+            listener.subscribe_at(&format!("http://{}", speaker), "10.0.0.5").await.unwrap();
+            assert_eq!(statuses.recv().await, Some(200));
+            assert_eq!(statuses.recv().await, Some(200));
+            let first = events.next().await.unwrap();
+            assert_eq!(first.speaker, "10.0.0.5");
+            assert_eq!(first.transport_state(), Some("PLAYING"));
+            assert_eq!(events.next().await.unwrap().volume(), Some(12));
+
+            // Without a pending subscription unknown ids are refused again
+            let stray = Client::new()
+                .request(Method::from_bytes(b"NOTIFY").unwrap(), format!("http://{}/10.0.0.5/AVTransport", listener.addr))
+                .header("SID", "uuid:unknown")
+                .body(AV_TRANSPORT_NOTIFY)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(stray.status(), StatusCode::PRECONDITION_FAILED);
+            listener.unsubscribe_all().await;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+pub mod volume_fade {
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::time::Duration;
+
+    /// Time between two volume changes
+    pub const FADE_STEP: Duration = Duration::from_millis(500);
+
+    /// How the volume moves from start to end over the fade
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum FadeCurve {
+        Linear,
+        /// Changes quickly at first and slows down towards the end
+        Logarithmic,
+        /// Starts and ends slowly, like a sunrise
+        SCurve,
+    }
+
+    impl FadeCurve {
+        /// Maps the progress of the fade (0 to 1) to the share of the volume change
+        fn apply(&self, t: f64) -> f64 {
+            let t = t.clamp(0.0, 1.0);
+            match self {
+                FadeCurve::Linear => t,
+                FadeCurve::Logarithmic => (1.0 + 9.0 * t).log10(),
+                FadeCurve::SCurve => t * t * (3.0 - 2.0 * t),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct FadeConfig {
+        /// Seconds the fade takes
+        duration: u32,
+        curve: FadeCurve,
+        /// Volume to fade in to, the speaker's current volume if not set.
+        /// Fading out always ends at 0.
+        target: Option<u16>,
+    }
+
+    impl ::std::default::Default for FadeConfig {
+        fn default() -> Self {
+            Self {
+                duration: 10,
+                curve: FadeCurve::Linear,
+                target: None,
+            }
+        }
+    }
+
+    impl fmt::Display for FadeConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
+
+    impl FadeConfig {
+        pub fn new(duration: u32, curve: FadeCurve, target: Option<u16>) -> Self {
+            Self { duration, curve, target }
+        }
+
+        pub fn duration(&self) -> Duration {
+            Duration::from_secs(self.duration as u64)
+        }
+
+        pub fn target(&self) -> Option<u16> {
+            self.target
+        }
+
+        /// Volume changes to fade from `from` to `to` with this configuration
+        pub fn schedule(&self, from: u16, to: u16) -> Vec<(Duration, u16)> {
+            schedule(from, to, self.duration(), self.curve)
+        }
+    }
+
+    /// Computes the volume changes of a fade as offsets from its start paired with
+    /// the volume to set then. Steps that would not change the volume are left out,
+    /// the last entry always sets `to` once `duration` has passed.
+    pub fn schedule(from: u16, to: u16, duration: Duration, curve: FadeCurve) -> Vec<(Duration, u16)> {
+        let mut steps: Vec<(Duration, u16)> = Vec::new();
+        let count = (duration.as_millis() / FADE_STEP.as_millis()) as u32;
+        let mut last = from;
+
+        for i in 1..count {
+            let at = FADE_STEP * i;
+            let share = curve.apply(at.as_secs_f64() / duration.as_secs_f64());
+            let volume = (from as f64 + (to as f64 - from as f64) * share).round() as u16;
+            if volume != last {
+                steps.push((at, volume));
+                last = volume;
+            }
+        }
+        if last != to || steps.is_empty() {
+            steps.push((duration, to));
+        }
+        steps
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn ms(millis: u64) -> Duration {
+            Duration::from_millis(millis)
+        }
+
+        #[test]
+        fn fades_linearly_up_and_down() {
+            let up = schedule(0, 10, Duration::from_secs(5), FadeCurve::Linear);
+            let expected: Vec<(Duration, u16)> = (1..=10).map(|i| (ms(500 * i as u64), i)).collect();
+            assert_eq!(up, expected);
+
+            let down = schedule(10, 0, Duration::from_secs(5), FadeCurve::Linear);
+            let expected: Vec<(Duration, u16)> = (1..=10).map(|i| (ms(500 * i as u64), 10 - i)).collect();
+            assert_eq!(down, expected);
+        }
+
+        #[test]
+        fn leaves_out_steps_without_change() {
+            let steps = schedule(0, 2, Duration::from_secs(5), FadeCurve::Linear);
+            assert_eq!(steps, vec![(ms(1500), 1), (ms(4000), 2)]);
+        }
+
+        #[test]
+        fn fades_logarithmically_up_and_down() {
+            let up = schedule(0, 100, Duration::from_secs(2), FadeCurve::Logarithmic);
+            assert_eq!(up, vec![(ms(500), 51), (ms(1000), 74), (ms(1500), 89), (ms(2000), 100)]);
+
+            let down = schedule(100, 0, Duration::from_secs(2), FadeCurve::Logarithmic);
+            assert_eq!(down, vec![(ms(500), 49), (ms(1000), 26), (ms(1500), 11), (ms(2000), 0)]);
+        }
+
+        #[test]
+        fn same_volume_is_set_once_at_the_end() {
+            assert_eq!(schedule(20, 20, Duration::from_secs(5), FadeCurve::Linear), vec![(ms(5000), 20)]);
+            assert_eq!(schedule(20, 20, Duration::from_secs(5), FadeCurve::SCurve), vec![(ms(5000), 20)]);
+        }
+
+        #[test]
+        fn zero_duration_jumps_to_the_target() {
+            assert_eq!(schedule(0, 30, Duration::ZERO, FadeCurve::Linear), vec![(Duration::ZERO, 30)]);
+            assert_eq!(schedule(30, 0, Duration::ZERO, FadeCurve::Logarithmic), vec![(Duration::ZERO, 0)]);
+        }
+
+        #[test]
+        fn config_uses_its_duration_and_curve() {
+            let fade = FadeConfig::new(2, FadeCurve::Logarithmic, None);
+            assert_eq!(fade.schedule(0, 100), schedule(0, 100, Duration::from_secs(2), FadeCurve::Logarithmic));
+        }
+    }
+}
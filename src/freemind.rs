@@ -1,12 +1,52 @@
 pub mod freemind_handler {
-    use cron::Schedule;
-    use chrono::{Local, TimeZone};
-    use reqwest::{Client, Response, header::HeaderValue};
+    use crate::clock::time_source::SharedClock;
+    use crate::recurrence::recurrence_rule::{Occurrence, Recurrence};
+    use crate::store::json_store::{JsonStore, Records};
+    use chrono::{DateTime, Duration, Local, LocalResult, NaiveDate, NaiveTime, Offset, TimeZone};
+    use reqwest::{Client, Response, StatusCode};
     use serde::{Deserialize, Serialize};
-    use std::cmp::{min, Ordering};
+    use std::collections::BTreeSet;
     use std::fmt;
-    use std::str::FromStr;
+    use std::fs;
+    use std::path::PathBuf;
     use quick_xml::de::from_str;
+    use quick_xml::se::to_string;
+
+    #[derive(Debug)]
+    pub enum FreemindError {
+        Http(reqwest::Error),
+        /// The server rejected the credentials with this status
+        Auth(u16),
+        Xml(String),
+        /// The server does not know the element with this id
+        NotFound(u16),
+        /// The element has no id, so the server can't tell which one is meant
+        MissingId,
+    }
+
+    impl fmt::Display for FreemindError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                FreemindError::Http(err) => write!(f, "Freemind request failed: {}", err),
+                FreemindError::Auth(status) => write!(f, "Freemind rejected the credentials ({})", status),
+                FreemindError::Xml(msg) => write!(f, "Invalid Freemind XML: {}", msg),
+                FreemindError::NotFound(id) => write!(f, "Freemind element {} does not exist", id),
+                FreemindError::MissingId => write!(f, "Freemind element has no id"),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for FreemindError {
+        fn from(err: reqwest::Error) -> Self {
+            FreemindError::Http(err)
+        }
+    }
+
+    impl From<quick_xml::DeError> for FreemindError {
+        fn from(err: quick_xml::DeError) -> Self {
+            FreemindError::Xml(err.to_string())
+        }
+    }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     enum FreemindAuth {
@@ -29,7 +69,10 @@ pub mod freemind_handler {
         server: String,
         username: String,
         secret: String,
-        method: FreemindAuth
+        method: FreemindAuth,
+        /// Clear the alert of elements once they were announced
+        #[serde(default)]
+        acknowledge: bool,
     }
 
     impl ::std::default::Default for FreemindConfig {
@@ -38,7 +81,8 @@ pub mod freemind_handler {
                 server: "https://example.com/api:8080".to_string(),
                 username: "username".to_string(),
                 secret: "password".to_string(),
-                method: FreemindAuth::Password
+                method: FreemindAuth::Password,
+                acknowledge: false,
             }
         }
     }
@@ -54,64 +98,89 @@ pub mod freemind_handler {
 
     #[derive(Serialize, Deserialize)]
     struct Registry {
-        #[serde(rename = "entry")]
+        #[serde(rename = "entry", default)]
         entries: Vec<AppElement>,
     }
 
     #[derive(Serialize, Deserialize)]
     #[serde(rename = "data")]
     struct Data {
-        #[serde(rename = "entry")]
+        #[serde(rename = "entry", default)]
         entries: Vec<AppElement>,
     }
 
     #[derive(Serialize, Deserialize)]
     #[serde(rename = "meta")]
     struct Meta {
-        #[serde(rename = "existing_ids")]
+        #[serde(rename = "existing_ids", default)]
         existing_ids: Vec<AppId>,
     }
 
     #[derive(Serialize, Deserialize)]
     struct AppId {
+        #[serde(default)]
         id: Vec<u16>,
     }
 
+    impl Part {
+        /// A part carrying `entries`, or only the `ids` for requests about elements
+        fn new(ids: Vec<u16>, entries: Vec<AppElement>) -> Self {
+            Self {
+                metadata: Meta {
+                    existing_ids: vec![AppId { id: ids }],
+                },
+                data: Data { entries },
+            }
+        }
+
+        /// The id the server gave a created element: the id of the element it
+        /// answers with, otherwise the only id it lists
+        fn created_id(&self) -> Option<u16> {
+            if let Some(id) = self.data.entries.iter().find_map(|e| e.id) {
+                return Some(id);
+            }
+            match self.metadata.existing_ids.iter().flat_map(|i| i.id.iter()).collect::<Vec<_>>()[..] {
+                [id] => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
     #[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
     pub struct Preparation {
+        #[serde(skip_serializing_if = "Option::is_none")]
         description: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         time: Option<u32>,
     }
 
     #[derive(Eq, Debug, Clone, Serialize, Deserialize)]
     #[serde(rename = "entry")]
     pub struct AppElement {
-        #[serde(skip)]
-        takes_place_on: Option<u32>,
-        #[serde(rename = "@id")]
+        #[serde(rename = "@id", skip_serializing_if = "Option::is_none")]
         id: Option<u16>,
         #[serde(rename = "name")]
         title: String,
         description: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         due: Option<u32>,
+        /// Cron expression, repetitions start at `due` if it is set
+        #[serde(skip_serializing_if = "Option::is_none")]
         repeats: Option<String>,
+        /// Last point in time a repeating element takes place
+        #[serde(rename = "until", default, skip_serializing_if = "Option::is_none")]
+        end: Option<u32>,
+        /// Times of repetitions that do not take place
+        #[serde(rename = "except", default, skip_serializing_if = "Vec::is_empty")]
+        exceptions: Vec<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         preparation: Option<Preparation>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         location: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         alert: Option<String>,
     }
 
-    impl PartialOrd for AppElement {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-
-    impl Ord for AppElement {
-        fn cmp(&self, other: &Self) -> Ordering {
-            self.takes_place_on.cmp(&other.takes_place_on)
-        }
-    }
-
     impl PartialEq for AppElement {
         fn eq(&self, other: &AppElement) -> bool {
             match self.id {
@@ -122,39 +191,163 @@ pub mod freemind_handler {
     }
 
     impl AppElement {
+        /// A new element to be created on the server
+        pub fn new(title: &str, description: &str, due: Option<u32>) -> Self {
+            Self {
+                id: None,
+                title: title.to_string(),
+                description: description.to_string(),
+                due,
+                repeats: None,
+                end: None,
+                exceptions: Vec::new(),
+                preparation: None,
+                location: None,
+                alert: None,
+            }
+        }
+
+        pub fn id(&self) -> Option<u16> {
+            self.id
+        }
+
+        pub fn set_alert(&mut self, alert: Option<String>) {
+            self.alert = alert;
+        }
+
         pub fn location(&self) -> &str {
             match &self.location {
-                Some(val) => &val,
-                None => &""
+                Some(val) => val,
+                None => ""
             }
         }
 
+        pub fn title(&self) -> &str {
+            &self.title
+        }
+
         pub fn description(&self) -> &str {
             &self.description
         }
 
-        pub fn timepoint(&self) -> Option<String> {
-            if let Some(mut takes_place) = self.takes_place_on {
-                if let Some(prepare) = &self.preparation {
-                    if let Some(prep) = prepare.time {
-                        takes_place += prep*60;
-                    }
-                }
+        pub fn preparation_description(&self) -> Option<&str> {
+            self.preparation.as_ref().and_then(|p| p.description.as_deref())
+        }
 
-                /*
-                return Some(format!("{}", chrono::Utc.timestamp_opt(takes_place as i64, 0)
-                    .unwrap()
-                    .with_timezone(&Local)
-                    .format("%H:%M")
-                ));
-                */
-                return match chrono::Utc.timestamp_opt(takes_place as i64, 0) {
-                    chrono::LocalResult::None => None,
-                    chrono::LocalResult::Single(val) => Some(val.with_timezone(&chrono::Local).format("%H:%M").to_string()),
-                    chrono::LocalResult::Ambiguous(val, _) => Some(val.with_timezone(&chrono::Local).format("%H:%M").to_string()),
-                };
+        /// Seconds needed to prepare for the event
+        fn preparation_time(&self) -> u32 {
+            self.preparation.as_ref().and_then(|p| p.time).unwrap_or(0) * 60
+        }
+
+        pub fn recurrence(&self) -> Recurrence {
+            Recurrence::new(self.due, self.repeats.as_deref(), self.end, &self.exceptions)
+        }
+
+        /// Every occurrence whose event takes place within `[from, until)`
+        pub fn occurrences(&self, from: u32, until: u32) -> Vec<Occurrence> {
+            let preparation = self.preparation_time();
+            self.recurrence()
+                .between(from, until)
+                .into_iter()
+                .map(|event| Occurrence::new(event, preparation))
+                .collect()
+        }
+
+        /// Alert times within `[from, until)`, none if the element has no alert
+        pub fn alerts(&self, from: u32, until: u32) -> Vec<u32> {
+            if self.alert.is_none() {
+                return Vec::new();
             }
-            None
+            let preparation = self.preparation_time();
+            self.occurrences(from.saturating_add(preparation), until.saturating_add(preparation))
+                .into_iter()
+                .map(|o| o.alert)
+                .collect()
+        }
+
+        /// The first alert time at or after `from` that `skip` does not reject
+        fn next_alert(&self, from: u32, skip: impl Fn(u32) -> bool) -> Option<u32> {
+            self.alert.as_ref()?;
+            let preparation = self.preparation_time();
+            self.recurrence()
+                .starting_at(from.saturating_add(preparation))
+                .map(|event| Occurrence::new(event, preparation).alert)
+                .find(|at| !skip(*at))
+        }
+    }
+
+    /// Every occurrence that was already announced or is being announced right
+    /// now, keyed by the element id and the timestamp of the occurrence
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct Announced {
+        announced: BTreeSet<(u16, u32)>,
+        /// Occurrences a run is announcing, with the time the reservation runs out
+        #[serde(default)]
+        reserved: Vec<(u16, u32, u32)>,
+    }
+
+    impl Records for Announced {
+        const RETENTION: u32 = 7 * 24 * 60 * 60;
+        const NAME: &'static str = "announcement record";
+
+        fn expire(&mut self, oldest: u32) {
+            self.announced.retain(|(_, at)| *at >= oldest);
+            self.reserved.retain(|(_, at, _)| *at >= oldest);
+        }
+    }
+
+    impl Announced {
+        /// A run that crashed while announcing gives its reservations up after this many seconds
+        const RESERVATION: u32 = 60 * 60;
+
+        pub fn contains(&self, id: u16, at: u32) -> bool {
+            self.announced.contains(&(id, at))
+        }
+
+        /// The occurrence was announced or another run is announcing it
+        fn is_taken(&self, id: u16, at: u32, now: u32) -> bool {
+            self.contains(id, at) || self.reserved.iter().any(|r| (r.0, r.1) == (id, at) && r.2 > now)
+        }
+
+        /// Reserves every one of `alerts` that nobody took yet and returns those
+        fn reserve(&mut self, alerts: &[(u16, u32)], now: u32) -> Vec<(u16, u32)> {
+            let free: Vec<(u16, u32)> = alerts.iter().copied().filter(|(id, at)| !self.is_taken(*id, *at, now)).collect();
+            self.reserved.retain(|(id, at, _)| !free.contains(&(*id, *at)));
+            self.reserved.extend(free.iter().map(|(id, at)| (*id, *at, now + Self::RESERVATION)));
+            free
+        }
+
+        /// Gives up the reservations of `alerts`, recording them as announced if they were
+        fn finish(&mut self, alerts: &[(u16, u32)], announced: bool) {
+            self.reserved.retain(|(id, at, _)| !alerts.contains(&(*id, *at)));
+            if announced {
+                self.announced.extend(alerts.iter().copied());
+            }
+        }
+    }
+
+    /// Keeps track of every occurrence that was already announced
+    pub type AnnouncedStore = JsonStore<Announced>;
+
+    /// The registry of the last successful fetch, kept on disk so alerts still go
+    /// off while the server can't be reached
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct RegistryCache {
+        /// When the registry was fetched
+        fetched_at: u32,
+        entries: Vec<AppElement>,
+    }
+
+    impl RegistryCache {
+        fn load(path: &PathBuf) -> Option<Self> {
+            let txt = fs::read_to_string(path).ok()?;
+            serde_json::from_str(&txt)
+                .map_err(|err| log::warn!("Ignoring unreadable registry cache {}: {}", path.display(), err))
+                .ok()
+        }
+
+        fn save(&self, path: &PathBuf) -> std::io::Result<()> {
+            fs::write(path, serde_json::to_string(self).unwrap())
         }
     }
 
@@ -163,19 +356,57 @@ pub mod freemind_handler {
         config: FreemindConfig,
         client: Option<Client>,
         elements: Vec<AppElement>,
+        announced: AnnouncedStore,
+        grace_period: u16,
+        clock: SharedClock,
+        cache: PathBuf,
+        /// When the current elements were fetched from the server
+        fetched_at: Option<u32>,
+        /// The last fetch failed and older elements are used
+        stale: bool,
+        /// Fetches don't update the registry cache
+        read_only: bool,
     }
 
     impl FreemindState {
-        pub fn new(config: FreemindConfig) -> Self {
+        /// Creates a new state, `state_file` records the occurrences already announced,
+        /// `cache_file` keeps the last fetched registry and missed occurrences are
+        /// caught up for `grace_period` minutes. Every decision about time is based on `clock`.
+        pub fn new(config: FreemindConfig, state_file: PathBuf, cache_file: PathBuf, grace_period: u16, clock: SharedClock) -> Self {
             Self {
                 config,
                 client: None,
                 elements: Vec::new(),
+                announced: AnnouncedStore::load(state_file),
+                grace_period,
+                clock,
+                cache: cache_file,
+                fetched_at: None,
+                stale: false,
+                read_only: false,
             }
         }
 
+        /// Leaves the registry cache as it is, for looking at the registry
+        /// without changing anything on disk
+        pub fn read_only(mut self) -> Self {
+            self.read_only = true;
+            self
+        }
+
+        #[allow(dead_code)]
         pub fn elements(&self) -> &Vec<AppElement> {
-            return &self.elements;
+            &self.elements
+        }
+
+        /// The elements could not be refreshed by the last fetch
+        pub fn is_stale(&self) -> bool {
+            self.stale
+        }
+
+        /// When the elements in use were fetched from the server
+        pub fn fetched_at(&self) -> Option<DateTime<Local>> {
+            self.fetched_at.and_then(|at| Local.timestamp_opt(at as i64, 0).single())
         }
 
         fn handle_empty_client(&mut self) {
@@ -215,7 +446,7 @@ pub mod freemind_handler {
                 .post(format!("{}{}", self.config.server, endpoint))
                 .header(
                     "user".to_string(),
-                    HeaderValue::from_str(&self.config.username).unwrap()
+                    &self.config.username
                 )
                 .header(
                     format!("{}", &self.config.method).to_lowercase(),
@@ -232,138 +463,621 @@ pub mod freemind_handler {
             Ok(res)
         }
 
-        /// Fetches the whole registry from the server
-        pub async fn fetch(&mut self) -> Result<(), reqwest::Error> {
-            let res: Response = self.call("/xml/fetch", "".to_string()).await?;
+        /// Returns the body of an XML response, `None` if the server sent something else
+        async fn xml_body(res: Response) -> Result<Option<String>, FreemindError> {
+            if res.status() == StatusCode::UNAUTHORIZED || res.status() == StatusCode::FORBIDDEN {
+                return Err(FreemindError::Auth(res.status().as_u16()));
+            }
+            let res = res.error_for_status()?;
+            let is_xml = res
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("text/xml"));
+            if is_xml {
+                Ok(Some(res.text().await?))
+            } else {
+                Ok(None)
+            }
+        }
 
-            let headers = res.headers();
-            if headers.get("content-type") == Some(&HeaderValue::from_static("text/xml")) {
-                let txt = res.text().await?;
+        /// Like `xml_body`, but anything other than XML is an error
+        async fn expect_xml(res: Response) -> Result<String, FreemindError> {
+            let content_type = res
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("nothing")
+                .to_string();
+            Self::xml_body(res)
+                .await?
+                .ok_or_else(|| FreemindError::Xml(format!("Expected XML but got {}", content_type)))
+        }
 
-                let fetched_registry: Registry = from_str(&txt).unwrap();
-                self.elements = fetched_registry.entries;
+        /// Sends `part` to `endpoint` and returns the part the server answers with
+        async fn exchange(&mut self, endpoint: &str, part: Part) -> Result<Option<Part>, FreemindError> {
+            let payload = to_string(&part).map_err(|err| FreemindError::Xml(err.to_string()))?;
+            let res = self.call(endpoint, payload).await?;
+            match Self::xml_body(res).await? {
+                Some(txt) if !txt.trim().is_empty() => Ok(Some(from_str(&txt)?)),
+                _ => Ok(None),
+            }
+        }
 
+        /// Fetches the whole registry from the server. If the server can't be reached,
+        /// the elements of the last successful fetch are kept, or loaded from the
+        /// cache, and marked as stale. Rejected credentials and a registry that
+        /// can't be read are always an error.
+        pub async fn fetch(&mut self) -> Result<(), FreemindError> {
+            let err = match self.fetch_registry().await {
+                Ok(entries) => {
+                    // When the fetch really happened, even while simulating another time
+                    let now = Local::now().timestamp().try_into().unwrap_or(0);
+                    let cache = RegistryCache { fetched_at: now, entries };
+                    if self.read_only {
+                        log::debug!("Not saving the registry cache");
+                    } else if let Err(err) = cache.save(&self.cache) {
+                        log::error!("Failed to save the registry cache: {}", err);
+                    }
+                    self.elements = cache.entries;
+                    self.fetched_at = Some(now);
+                    self.stale = false;
+                    self.sort_by_due();
+                    return Ok(());
+                }
+                Err(err @ FreemindError::Http(_)) => err,
+                Err(err) => return Err(err),
+            };
+
+            if self.fetched_at.is_none() {
+                let cache = match RegistryCache::load(&self.cache) {
+                    Some(cache) => cache,
+                    None => return Err(err),
+                };
+                self.elements = cache.entries;
+                self.fetched_at = Some(cache.fetched_at);
                 self.sort_by_due();
             }
+            log::warn!("{}", err);
+            self.stale = true;
+            log::warn!(
+                "Using the registry fetched at {}",
+                self.fetched_at()
+                    .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default()
+            );
+            Ok(())
+        }
+
+        async fn fetch_registry(&mut self) -> Result<Vec<AppElement>, FreemindError> {
+            let res: Response = self.call("/xml/fetch", "".to_string()).await?;
+            let txt = Self::expect_xml(res).await?;
+            let fetched_registry: Registry = from_str(&txt)?;
+            Ok(fetched_registry.entries)
+        }
+
+        /// Fetches a single element and updates the local copy of it
+        pub async fn fetch_by_id(&mut self, id: u16) -> Result<AppElement, FreemindError> {
+            let element = self
+                .exchange("/xml/fetch", Part::new(vec![id], Vec::new()))
+                .await?
+                .and_then(|part| part.data.entries.into_iter().find(|e| e.id == Some(id)))
+                .ok_or(FreemindError::NotFound(id))?;
+            self.replace_local(element.clone());
+            Ok(element)
+        }
+
+        /// Fetches all elements the server considers due today
+        pub async fn due_today(&mut self) -> Result<Vec<AppElement>, FreemindError> {
+            let res: Response = self.call("/xml/due/today", "".to_string()).await?;
+            let part: Part = from_str(&Self::expect_xml(res).await?)?;
+            Ok(part.data.entries)
+        }
+
+        /// Creates `element` on the server and returns the id the server answers
+        /// with, `None` if it does not tell
+        pub async fn create(&mut self, mut element: AppElement) -> Result<Option<u16>, FreemindError> {
+            element.id = None;
+            let answer = self.exchange("/xml/update", Part::new(Vec::new(), vec![element.clone()])).await?;
+            let id = answer.and_then(|part| part.created_id());
+            match id {
+                Some(id) => {
+                    element.id = Some(id);
+                    self.replace_local(element);
+                }
+                None => log::warn!("The server did not tell the id of the created element {}", element.title),
+            }
+            Ok(id)
+        }
 
+        /// Replaces the element with the id of `element` on the server
+        pub async fn update(&mut self, element: AppElement) -> Result<(), FreemindError> {
+            let id = element.id.ok_or(FreemindError::MissingId)?;
+            self.exchange("/xml/update", Part::new(vec![id], vec![element.clone()])).await?;
+            self.replace_local(element);
             Ok(())
         }
 
-        /// Computes for every element the actual time in which it takes place
-        fn compute_takes_place(&mut self) {
-            self.elements
-                .iter_mut()
-                .for_each(|e: &mut AppElement| {
-                    let mut due = e.due;
-
-                    if let Some(repeat) = &e.repeats {
-                        if let Ok(schedule) = Schedule::from_str(repeat) {
-                            if let Some(next_occasion) = schedule.upcoming(Local).next() {
-                                let next_due: u32 = next_occasion.naive_utc().and_utc().timestamp().try_into().unwrap_or(u32::MAX);
-                                due = Some(min(due.unwrap_or(u32::MAX), next_due));
-                            };
-                        };
-                    };
-
-                    if due.is_some() {
-                        if let Some(prep) = &e.preparation {
-                            if let Some(delta) = prep.time {
-                                due = Some(due.unwrap() - delta*60);
-                            }
-                        }
-                    }
-                    e.takes_place_on = due;
-                });
+        /// Deletes the elements with `ids` on the server
+        pub async fn delete(&mut self, ids: &[u16]) -> Result<(), FreemindError> {
+            self.exchange("/xml/delete", Part::new(ids.to_vec(), Vec::new())).await?;
+            self.elements.retain(|e| e.id.is_none_or(|id| !ids.contains(&id)));
+            Ok(())
+        }
+
+        /// Marks the alert of the element with `id` as done by clearing it, so it
+        /// won't be announced again
+        pub async fn acknowledge(&mut self, id: u16) -> Result<(), FreemindError> {
+            let mut element = match self.elements.iter().find(|e| e.id == Some(id)) {
+                Some(element) => element.clone(),
+                None => self.fetch_by_id(id).await?,
+            };
+            element.set_alert(None);
+            self.update(element).await
         }
 
-        /// Determines whether an alert should be triggered or not.
-        pub fn needs_trigger(&mut self, interval: u16) -> bool {
-            let now: u32 = chrono::offset::Local::now()
-                .naive_utc()
-                .timestamp()
-                .try_into()
-                .unwrap_or(0);
+        /// Acknowledges every element of `alerts` if configured to
+        pub async fn acknowledge_alerts(&mut self, alerts: &[(u16, u32)]) {
+            if !self.config.acknowledge {
+                return;
+            }
+            for (id, _) in alerts {
+                // Repeating elements keep alerting for their next occurrences
+                if self.elements.iter().any(|e| e.id == Some(*id) && e.repeats.is_some()) {
+                    continue;
+                }
+                match self.acknowledge(*id).await {
+                    Ok(()) => log::info!("Acknowledged element {}", id),
+                    Err(err) => log::error!("Failed to acknowledge element {}: {}", id, err),
+                }
+            }
+        }
 
-            self.compute_takes_place();
+        fn replace_local(&mut self, element: AppElement) {
+            match self.elements.iter_mut().find(|e| e.id.is_some() && e.id == element.id) {
+                Some(local) => *local = element,
+                None => self.elements.push(element),
+            }
+            self.sort_by_due();
+        }
 
-            self.elements()
+        /// Start of the window in which missed occurrences are still announced
+        fn catch_up_start(&self, now: u32) -> u32 {
+            now.saturating_sub(self.grace_period as u32 * 60)
+        }
+
+        /// Returns the (id, timestamp) of every alerting occurrence in `[from, until)`
+        /// that no run announced or is announcing. Elements without an id can't be told
+        /// apart in the announcement record and are left out.
+        fn pending_alerts(&self, from: u32, until: u32) -> Vec<(u16, u32)> {
+            let now = self.clock.unix();
+            let mut pending: Vec<(u16, u32)> = self
+                .elements
                 .iter()
-                .filter(|e| e.takes_place_on.is_some() && e.alert.is_some())
-                .filter(|e| {
-                    e.takes_place_on.unwrap_or(0) >= now &&
-                    e.takes_place_on.unwrap_or(0) < (now + (interval*60) as u32)
-                })
-                .next()
-                .is_some()
-        }
-
-        /// Parses the available information and returns all Elements that take place today
-        /// and sorts them when they occur
-        pub fn get_today(&mut self) -> Vec<&AppElement> {
-            let mut result: Vec<&AppElement>;
-
-            let now: chrono::DateTime<Local> = chrono::offset::Local::now();
-
-            let today_start: u32 = now
-                //.date_naive()
-                //.and_hms_opt(0,0,0)
-                //.unwrap()
-                //.and_local_timezone(Local)
-                //.unwrap()
-                .naive_utc()
-                .and_utc()
-                .timestamp()
-                .try_into()
-                .unwrap_or(0);
-            let today_end: u32 = now
-                .date_naive()
-                .and_hms_opt(23, 59, 59)
-                .unwrap()
-                .and_local_timezone(Local)
-                .unwrap()
-                .naive_utc()
-                .and_utc()
-                .timestamp()
-                .try_into()
-                .unwrap_or(u32::MAX);
-
-            self.compute_takes_place();
-
-            result = self.elements()
+                .filter_map(|e| e.id.map(|id| (id, e)))
+                .flat_map(|(id, e)| e.alerts(from, until).into_iter().map(move |at| (id, at)))
+                .filter(|(id, at)| !self.announced.records().is_taken(*id, *at, now))
+                .collect();
+            pending.sort_by_key(|(_, at)| *at);
+            pending
+        }
+
+        /// Returns the (id, timestamp) of every unannounced alert taking place before
+        /// `until`, going back at most the grace period.
+        pub fn due_alerts(&self, until: u32) -> Vec<(u16, u32)> {
+            let now = self.clock.unix();
+            self.pending_alerts(self.catch_up_start(now), until)
+        }
+
+        /// Reserves `alerts` for this run before announcing them and returns those
+        /// no other run announced or is announcing. The record on disk is read
+        /// again first, so runs at the same time don't announce twice.
+        pub fn reserve(&mut self, alerts: &[(u16, u32)]) -> Vec<(u16, u32)> {
+            let now = self.clock.unix();
+            match self.announced.update(now, |announced| announced.reserve(alerts, now)) {
+                Ok(reserved) => reserved,
+                Err(err) => {
+                    log::error!("Failed to reserve the alerts: {}", err);
+                    alerts.to_vec()
+                }
+            }
+        }
+
+        /// Records the reserved `alerts` as announced, so they are not announced again
+        pub fn mark_announced(&mut self, alerts: &[(u16, u32)]) {
+            alerts.iter().for_each(|(id, at)| log::debug!("Announced occurrence {} of element {}", at, id));
+            self.finish(alerts, true);
+        }
+
+        /// Gives up the reservation of `alerts` after a failed announcement, so it is
+        /// retried while it is within the grace period
+        pub fn release(&mut self, alerts: &[(u16, u32)]) {
+            self.finish(alerts, false);
+        }
+
+        fn finish(&mut self, alerts: &[(u16, u32)], announced: bool) {
+            if alerts.is_empty() {
+                return;
+            }
+            if let Err(err) = self.announced.update(self.clock.unix(), |a| a.finish(alerts, announced)) {
+                log::error!("Failed to save the announcement record: {}", err);
+            }
+        }
+
+        /// Reads what other runs announced meanwhile
+        pub fn reload_announced(&mut self) {
+            self.announced.reload();
+        }
+
+        pub fn is_announced(&self, id: u16, at: u32) -> bool {
+            self.announced.records().contains(id, at)
+        }
+
+        pub fn element(&self, id: u16) -> Option<&AppElement> {
+            self.elements.iter().find(|e| e.id == Some(id))
+        }
+
+        /// Determines which alerts should be triggered, if any.
+        pub fn needs_trigger(&self, interval: u16) -> Vec<(u16, u32)> {
+            let now = self.clock.unix();
+
+            self.due_alerts(now + (interval as u32 * 60))
+        }
+
+        /// The alert setting of the element with `id`
+        pub fn alert(&self, id: u16) -> Option<&str> {
+            self.element(id).and_then(|e| e.alert.as_deref())
+        }
+
+        /// Returns the earliest point in time on which an unannounced alert takes place.
+        /// Occurrences missed within the grace period are returned as well.
+        pub fn next_alert(&self, now: u32) -> Option<u32> {
+            let from = self.catch_up_start(now);
+            self.elements
                 .iter()
-                .filter(|e| e.takes_place_on.is_some())
-                .filter(|e| {
-                    e.takes_place_on.unwrap_or(0) >= today_start &&
-                    e.takes_place_on.unwrap_or(0) <= today_end
+                .filter_map(|e| {
+                    let id = e.id?;
+                    e.next_alert(from, |at| self.announced.records().is_taken(id, at, now))
                 })
+                .min()
+        }
+
+        /// Every occurrence whose event takes place within `[from, until)`, in the
+        /// order they take place
+        pub fn occurrences_between(&self, from: u32, until: u32) -> Vec<(&AppElement, Occurrence)> {
+            let mut result: Vec<(&AppElement, Occurrence)> = self
+                .elements
+                .iter()
+                .flat_map(|e| e.occurrences(from, until).into_iter().map(move |o| (e, o)))
                 .collect();
+            result.sort_by_key(|(_, o)| o.event);
+            result
+        }
 
-            result.sort();
+        /// Every occurrence taking place on `day`
+        pub fn occurrences_on(&self, day: NaiveDate) -> Vec<(&AppElement, Occurrence)> {
+            let (from, until) = day_window(day);
+            self.occurrences_between(from, until)
+        }
 
-            return result;
+        /// Every occurrence taking place today, including those already over
+        pub fn get_today(&self) -> Vec<(&AppElement, Occurrence)> {
+            self.occurrences_on(self.clock.now().date_naive())
         }
+    }
 
-        /*
-        /// Fetches all Entries due today
-        pub async fn today(&mut self) -> Result<(), reqwest::Error> {
-            let res: Response = self.call("/xml/due/today", "".to_string()).await?;
+    /// The first moment of `day` in local time. Where a DST change skips
+    /// midnight, the day starts when the clocks jump forward.
+    fn start_of_day(day: NaiveDate) -> Option<DateTime<Local>> {
+        let midnight = day.and_time(NaiveTime::MIN);
+        match Local.from_local_datetime(&midnight) {
+            LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t),
+            LocalResult::None => {
+                // Midnight read with the offset before the jump is the moment of the jump
+                let before = Local.from_local_datetime(&(midnight - Duration::hours(12))).earliest()?;
+                Some(Local.from_utc_datetime(&(midnight - before.offset().fix())))
+            }
+        }
+    }
 
-            let headers = res.headers();
-            if headers.get("content-type") == Some(&HeaderValue::from_static("text/xml")) {
-                let txt = res.text().await?;
+    /// Start and end of `day` in local time as unix timestamps
+    pub fn day_window(day: NaiveDate) -> (u32, u32) {
+        let start_of = |day: NaiveDate| -> u32 {
+            start_of_day(day)
+                .map(|t| t.timestamp().try_into().unwrap_or(0))
+                .unwrap_or(0)
+        };
+        let next = day.succ_opt().unwrap_or(day);
+        (start_of(day), start_of(next))
+    }
 
-                let fetched_part: Part = from_str(&txt).unwrap();
-                self.elements = fetched_part.data.entries;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::time_source::{local_time_zone, FixedClock};
+        use std::sync::Arc;
 
-                self.sort_by_due();
-            }
+        fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+            NaiveDate::from_ymd_opt(y, m, d).unwrap()
+        }
 
-            Ok(())
+        #[test]
+        fn days_start_at_midnight() {
+            let _tz = local_time_zone("Europe/Berlin");
+            // 2024-03-05 00:00 +01:00 until 2024-03-06 00:00 +01:00
+            assert_eq!(day_window(date(2024, 3, 5)), (1_709_593_200, 1_709_679_600));
+            // Spring forward at 02:00 makes the day an hour shorter
+            let (from, until) = day_window(date(2024, 3, 31));
+            assert_eq!(until - from, 23 * 60 * 60);
         }
-        */
 
+        #[test]
+        fn days_start_after_a_skipped_midnight() {
+            let _tz = local_time_zone("America/Santiago");
+            // 2024-09-08 00:00 does not exist, the clocks jump from 00:00 -04 to 01:00 -03
+            let (from, until) = day_window(date(2024, 9, 8));
+            assert_eq!(from, 1_725_768_000);
+            assert_eq!(until, 1_725_850_800);
+            assert_eq!(day_window(date(2024, 9, 7)), (1_725_681_600, 1_725_768_000));
+        }
 
-    }
+        /// Every morning at 07:00 from 2024-03-01 on, with an alert
+        const DAILY: &str = "<registry><entry id=\"1\"><name>Walk</name><description>Around the block</description>\
+            <due>1709272800</due><repeats>0 0 7 * * * *</repeats><alert>default</alert></entry></registry>";
+
+        /// A state with the `DAILY` element at `now` in Europe/Berlin, nothing announced yet
+        fn berlin_at(now: &str) -> FreemindState {
+            let now = crate::clock::time_source::parse_time(now).unwrap();
+            let dir = std::env::temp_dir().join(format!("freemind-test-{}", std::process::id()));
+            let mut state = FreemindState::new(
+                FreemindConfig::default(),
+                dir.join("announced.json"),
+                dir.join("registry.json"),
+                30,
+                Arc::new(FixedClock(now)),
+            );
+            state.elements = from_str::<Registry>(DAILY).unwrap().entries;
+            state
+        }
 
+        fn events(state: &FreemindState) -> Vec<u32> {
+            state.get_today().iter().map(|(_, o)| o.event).collect()
+        }
+
+        #[test]
+        fn alerts_at_local_time_after_spring_forward() {
+            let _tz = local_time_zone("Europe/Berlin");
+            // 2024-03-31 07:00 +02:00, the clocks went from 02:00 to 03:00 that night
+            let state = berlin_at("2024-03-31 06:30");
+            assert_eq!(state.needs_trigger(60), vec![(1, 1_711_861_200)]);
+            assert_eq!(state.needs_trigger(15), Vec::new());
+            assert_eq!(events(&state), vec![1_711_861_200]);
+            // The day before still alerts at 07:00 +01:00, 23 hours earlier
+            assert_eq!(events(&berlin_at("2024-03-30 23:59")), vec![1_711_861_200 - 23 * 60 * 60]);
+        }
 
-}
\ No newline at end of file
+        #[test]
+        fn alerts_at_local_time_after_fall_back() {
+            let _tz = local_time_zone("Europe/Berlin");
+            // 2024-10-27 07:00 +01:00, the clocks went from 03:00 back to 02:00 that night
+            let state = berlin_at("2024-10-27 06:30");
+            assert_eq!(state.needs_trigger(60), vec![(1, 1_730_008_800)]);
+            assert_eq!(events(&state), vec![1_730_008_800]);
+            // During the repeated hour it is the same day with the same single event
+            let state = berlin_at("2024-10-27 02:30");
+            assert_eq!(state.needs_trigger(60), Vec::new());
+            assert_eq!(events(&state), vec![1_730_008_800]);
+            assert_eq!(events(&berlin_at("2024-10-28 00:00")), vec![1_730_008_800 + 24 * 60 * 60]);
+        }
+
+        #[test]
+        fn missed_alerts_are_caught_up_within_the_grace_period() {
+            let _tz = local_time_zone("Europe/Berlin");
+            assert_eq!(berlin_at("2024-03-31 07:20").needs_trigger(5), vec![(1, 1_711_861_200)]);
+            assert_eq!(berlin_at("2024-03-31 07:40").needs_trigger(5), Vec::new());
+        }
+
+        #[test]
+        fn runs_at_the_same_time_announce_once() {
+            let _tz = local_time_zone("Europe/Berlin");
+            let dir = std::env::temp_dir().join(format!("freemind-announced-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let state = |now: &str| {
+                let mut state = berlin_at(now);
+                state.announced = AnnouncedStore::load(dir.join("announced.json"));
+                state
+            };
+            let mut cron = state("2024-03-31 06:58");
+            let mut daemon = state("2024-03-31 07:00");
+            let alert = vec![(1, 1_711_861_200)];
+
+            assert_eq!(cron.reserve(&alert), alert);
+            // The daemon sees the reservation and neither plays nor waits for it
+            daemon.reload_announced();
+            assert_eq!(daemon.needs_trigger(5), Vec::new());
+            assert_eq!(daemon.next_alert(1_711_861_200), Some(1_711_861_200 + 24 * 60 * 60));
+            assert_eq!(daemon.reserve(&alert), Vec::new());
+
+            // A failed announcement can be retried by anyone
+            cron.release(&alert);
+            assert_eq!(daemon.reserve(&alert), alert);
+            daemon.mark_announced(&alert);
+            let mut later = state("2024-03-31 07:05");
+            assert_eq!(later.reserve(&alert), Vec::new());
+            fs::remove_dir_all(&dir).unwrap();
+            assert!(later.is_announced(1, 1_711_861_200));
+            assert_eq!(later.needs_trigger(5), Vec::new());
+        }
+
+        const FULL: &str = "<part><meta><existing_ids><id>4</id></existing_ids></meta><data>\
+            <entry id=\"4\"><name>Dentist &amp; more</name><description>Check-up</description><due>1700000000</due>\
+            <repeats>0 0 7 * * Mon *</repeats><until>1800000000</until><except>1700600000</except><except>1701200000</except>\
+            <preparation><description>Brush teeth</description><time>15</time></preparation>\
+            <location>Main Street</location><alert>default</alert></entry></data></part>";
+
+        #[test]
+        fn parses_every_field() {
+            let part: Part = from_str(FULL).unwrap();
+            let element = &part.data.entries[0];
+            assert_eq!(element.id, Some(4));
+            assert_eq!(element.title(), "Dentist & more");
+            assert_eq!(element.description(), "Check-up");
+            assert_eq!(element.due, Some(1_700_000_000));
+            assert_eq!(element.repeats.as_deref(), Some("0 0 7 * * Mon *"));
+            assert_eq!(element.end, Some(1_800_000_000));
+            assert_eq!(element.exceptions, vec![1_700_600_000, 1_701_200_000]);
+            assert_eq!(element.preparation_description(), Some("Brush teeth"));
+            assert_eq!(element.preparation_time(), 15 * 60);
+            assert_eq!(element.location(), "Main Street");
+            assert_eq!(element.alert.as_deref(), Some("default"));
+        }
+
+        #[test]
+        fn round_trips_a_part() {
+            let part: Part = from_str(FULL).unwrap();
+            let xml = to_string(&part).unwrap();
+            assert_eq!(xml, FULL);
+            let again: Part = from_str(&xml).unwrap();
+            assert_eq!(format!("{:?}", again.data.entries), format!("{:?}", part.data.entries));
+        }
+
+        #[test]
+        fn leaves_out_missing_fields() {
+            let xml = "<part><meta><existing_ids/></meta><data><entry><name>Note</name><description>Just text</description></entry></data></part>";
+            let part: Part = from_str(xml).unwrap();
+            let element = &part.data.entries[0];
+            assert_eq!(element.id, None);
+            assert_eq!(element.due, None);
+            assert!(element.exceptions.is_empty());
+            assert_eq!(element.preparation_time(), 0);
+            assert_eq!(element.location(), "");
+
+            let xml = to_string(&Part::new(vec![4], part.data.entries.clone())).unwrap();
+            assert_eq!(
+                xml,
+                "<part><meta><existing_ids><id>4</id></existing_ids></meta><data><entry><name>Note</name><description>Just text</description></entry></data></part>"
+            );
+        }
+
+        #[test]
+        fn requests_only_carry_ids() {
+            let xml = to_string(&Part::new(vec![3, 5], Vec::new())).unwrap();
+            assert_eq!(xml, "<part><meta><existing_ids><id>3</id><id>5</id></existing_ids></meta><data/></part>");
+            let part: Part = from_str(&xml).unwrap();
+            assert_eq!(part.metadata.existing_ids[0].id, vec![3, 5]);
+            assert!(part.data.entries.is_empty());
+        }
+
+        #[test]
+        fn creating_sends_no_id() {
+            let mut element = AppElement::new("Bins", "Take out the bins", Some(1_700_000_000));
+            element.set_alert(Some("default".to_string()));
+            let xml = to_string(&Part::new(Vec::new(), vec![element])).unwrap();
+            assert_eq!(
+                xml,
+                "<part><meta><existing_ids/></meta><data><entry><name>Bins</name><description>Take out the bins</description>\
+                 <due>1700000000</due><alert>default</alert></entry></data></part>"
+            );
+        }
+
+        /// A Freemind server that answers every request with `answer` and hands
+        /// out the path and body of each request it got
+        async fn fake_server(answer: &'static str) -> (String, tokio::sync::mpsc::UnboundedReceiver<(String, String)>) {
+            use hyper::service::{make_service_fn, service_fn};
+            use hyper::{Body, Request, Server};
+            use std::convert::Infallible;
+
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let make_svc = make_service_fn(move |_conn| {
+                let tx = tx.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let tx = tx.clone();
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            tx.send((path, String::from_utf8_lossy(&body).to_string())).unwrap();
+                            let res = hyper::Response::builder().header("content-type", "text/xml").body(Body::from(answer));
+                            Ok::<_, Infallible>(res.unwrap())
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+            let url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            (url, rx)
+        }
+
+        #[tokio::test]
+        async fn creates_elements_on_the_server() {
+            let (server, mut requests) = fake_server("<part><meta><existing_ids><id>7</id></existing_ids></meta><data/></part>").await;
+            let mut state = berlin_at("2024-03-05 06:30");
+            state.config.server = server;
+
+            let id = state.create(AppElement::new("Bins", "Take out the bins", Some(1_709_700_000))).await.unwrap();
+            assert_eq!(id, Some(7));
+            let (path, body) = requests.recv().await.unwrap();
+            assert_eq!(path, "/xml/update");
+            assert!(body.contains("<data><entry><name>Bins</name>"), "{}", body);
+            let ids: Vec<Option<u16>> = state.elements().iter().map(|e| e.id).collect();
+            assert_eq!(ids, vec![Some(1), Some(7)]);
+        }
+
+        #[test]
+        fn reads_the_id_of_a_created_element() {
+            let answer = |xml: &str| from_str::<Part>(xml).unwrap().created_id();
+            let echoed = "<part><meta><existing_ids><id>3</id><id>9</id></existing_ids></meta>\
+                <data><entry id=\"9\"><name>Bins</name><description>b</description></entry></data></part>";
+            assert_eq!(answer(echoed), Some(9));
+            assert_eq!(answer("<part><meta><existing_ids><id>9</id></existing_ids></meta><data/></part>"), Some(9));
+            // Several ids without the element can't tell which one is new
+            assert_eq!(answer("<part><meta><existing_ids><id>3</id><id>9</id></existing_ids></meta><data/></part>"), None);
+            assert_eq!(answer("<part><meta><existing_ids/></meta><data/></part>"), None);
+        }
+
+        #[test]
+        fn parses_a_registry() {
+            let xml = "<registry><entry id=\"1\"><name>A</name><description>a</description><due>10</due></entry>\
+                <entry id=\"2\"><name>B</name><description>b</description></entry></registry>";
+            let registry: Registry = from_str(xml).unwrap();
+            let ids: Vec<Option<u16>> = registry.entries.iter().map(|e| e.id).collect();
+            assert_eq!(ids, vec![Some(1), Some(2)]);
+            assert!(from_str::<Registry>("<registry><entry id=\"x\"/></registry>").is_err());
+            assert!(from_str::<Registry>("<registry/>").unwrap().entries.is_empty());
+        }
+
+        #[tokio::test]
+        async fn fetches_an_empty_registry() {
+            let (server, _requests) = fake_server("<registry></registry>").await;
+            let mut state = berlin_at("2024-03-05 06:30");
+            state.config.server = server;
+            state.cache = std::env::temp_dir().join(format!("freemind-empty-{}.json", std::process::id()));
+            state.fetch().await.unwrap();
+            fs::remove_file(&state.cache).unwrap();
+            assert!(state.elements().is_empty());
+            assert!(!state.is_stale());
+        }
+
+        #[tokio::test]
+        async fn read_only_states_keep_the_cache() {
+            let (server, _requests) = fake_server(DAILY).await;
+            let mut state = berlin_at("2024-03-05 06:30").read_only();
+            state.config.server = server;
+            state.cache = std::env::temp_dir().join(format!("freemind-read-only-{}.json", std::process::id()));
+            state.fetch().await.unwrap();
+            assert_eq!(state.elements().len(), 1);
+            assert!(!state.cache.exists());
+        }
+
+        #[tokio::test]
+        async fn unreadable_registries_are_an_error() {
+            let (server, _requests) = fake_server("<registry><entry id=\"x\"/></registry>").await;
+            let mut state = berlin_at("2024-03-05 06:30");
+            state.config.server = server;
+            state.fetched_at = Some(1_709_613_000);
+            assert!(matches!(state.fetch().await, Err(FreemindError::Xml(_))));
+            assert_eq!(state.elements().len(), 1);
+        }
+    }
+}
@@ -1,44 +1,105 @@
 mod speaker;
-pub use crate::speaker::box_controller::SpeakerBox;
+pub use crate::speaker::box_controller::{discover_speakers, group_speakers, position, speaker_ip, xml_escape, QueueBuilder, SpeakerBox, SpeakerSnapshot, TrackEnd, TrackWatch};
 
 mod freemind;
 pub use crate::freemind::freemind_handler::FreemindConfig;
 
 mod content;
-pub use crate::content::speech::{get_date, get_speech_voicerss};
-pub use crate::content::music::{JellyfinConfig, get_random_jellyfin_track};
+pub use crate::content::speech::{synthesize_with_fallback, SpeechConfig, TtsBackend, TtsEngine, TtsError, Utterance};
+pub use crate::content::music::{select_source, JellyfinConfig, MusicBackend, MusicContext, MusicSource, PlayHistory, Resume, SelectionConfig, Track};
+pub use crate::content::template::{BriefingContext, Templates};
 
-use freemind::freemind_handler::FreemindState;
+mod server;
+pub use crate::server::media_server::{mime_type, MediaServer, MediaServerConfig};
+
+mod events;
+pub use crate::events::event_listener::{EventConfig, EventListener, SpeakerEvent};
+
+mod didl;
+pub use crate::didl::didl_lite::{DidlItem, AUDIO_ITEM};
+
+mod error;
+pub use crate::error::app_error::AppError;
+
+mod recurrence;
+
+mod store;
+
+mod clock;
+pub use crate::clock::time_source::{parse_time, system_clock, Clock, FixedClock, SharedClock};
+
+mod fade;
+pub use crate::fade::volume_fade::{FadeConfig, FadeCurve};
+
+use freemind::freemind_handler::{AppElement, FreemindState};
+use futures_util::stream::{BoxStream, StreamExt};
 use sonor::{args, rupnp::ssdp::URN, Speaker};
+use chrono::TimeZone;
 use clap::{Arg, Command};
-use confy;
-use env_logger::{self, Builder};
+use env_logger::Builder;
+use ipnetwork::Ipv4Network;
 use log::LevelFilter;
 use pnet::datalink::interfaces;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use tokio::time::sleep_until;
+use tokio::time::{sleep, sleep_until, timeout_at};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
-use tokio;
 
 
 const AV_TRANSPORT: &URN = &URN::service("schemas-upnp-org", "AVTransport", 1);
+const CONFIG_FILE: &str = "./FreemindSonos.config";
+/// How long the daemon waits before retrying a failed announcement
+const RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 struct Config {
     username: String,
     local_server: String,
     path: PathBuf,
-    tts_api_key: String,
     exec_inverval: u16,
+    refresh_interval: u16,
+    grace_period: u16,
+    /// Seconds the wake-up music plays before the briefing
+    music_duration: u32,
+    /// Keep playing after `music_duration` until the current track ends
+    finish_track: bool,
+    /// Seconds of music in total, what is left after the briefing is played
+    /// afterwards by resuming the track. 0 disables resuming. In queue mode the
+    /// music after the briefing is faded out after this long, 0 plays the whole queue.
+    listening_time: u32,
+    /// Play chime, briefing and music from the queue instead of music first
+    queue_mode: bool,
+    /// Sound in the media directory played before the briefing in queue mode
+    chime: String,
+    /// Tracks queued after the briefing in queue mode
+    queue_tracks: u8,
+    /// Mention in the briefing when the registry could not be refreshed
+    stale_note: bool,
+    /// Deprecated, the VoiceRSS key of configs from before `tts`. Moved into the
+    /// first VoiceRSS backend when loading.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tts_api_key: Option<String>,
+    tts: Vec<TtsBackend>,
+    /// Alarms play the first source unless `music_for` names another one
+    music: Vec<MusicBackend>,
+    /// Element titles mapped to the name of the music source their alarms play
+    music_for: BTreeMap<String, String>,
+    speech: SpeechConfig,
+    fade_in: FadeConfig,
+    fade_out: FadeConfig,
     freemind: FreemindConfig,
     jellyfin: JellyfinConfig,
-    speaker: SpeakerBox,
+    selection: SelectionConfig,
+    media_server: MediaServerConfig,
+    events: EventConfig,
+    /// The first speaker coordinates the group all others join
+    #[serde(alias = "speaker", deserialize_with = "speaker::box_controller::one_or_many")]
+    speakers: Vec<SpeakerBox>,
 }
 impl ::std::default::Default for Config {
     fn default() -> Self {
@@ -46,11 +107,29 @@ impl ::std::default::Default for Config {
             username: "doncato".to_string(),
             local_server: "http://192.168.0.1/media".to_string(),
             path: PathBuf::from("./media"),
-            tts_api_key: "YOUR TTS API KEY".to_string(),
             exec_inverval: 5,
+            refresh_interval: 15,
+            grace_period: 30,
+            music_duration: 120,
+            finish_track: false,
+            listening_time: 300,
+            queue_mode: false,
+            chime: String::new(),
+            queue_tracks: 3,
+            stale_note: true,
+            tts_api_key: None,
+            tts: vec![TtsBackend::default()],
+            music: vec![MusicBackend::default()],
+            music_for: BTreeMap::new(),
+            speech: SpeechConfig::default(),
+            fade_in: FadeConfig::default(),
+            fade_out: FadeConfig::new(5, FadeCurve::Linear, None),
             freemind: FreemindConfig::default(),
             jellyfin: JellyfinConfig::default(),
-            speaker: SpeakerBox::default(),
+            selection: SelectionConfig::default(),
+            media_server: MediaServerConfig::default(),
+            events: EventConfig::default(),
+            speakers: vec![SpeakerBox::default()],
         }
     }
 }
@@ -61,16 +140,18 @@ impl fmt::Display for Config {
 }
 impl Config {
     /// Iterates over every speaker configuration in the config and converts them
-    /// into a Vector of sonor::Speaker objects.
-    async fn to_speaker(&self) -> Option<Speaker> {
-        let mut result = None;
-        log::debug!("Connecting to {} . . .", self.speaker.ip());
-            if let Some(spk) = self.speaker.to_speaker().await {
-                result = Some(spk);
-                log::debug!("Successfully connected to {}.", self.speaker.ip());
+    /// into a Vector of sonor::Speaker objects, paired with their configuration.
+    async fn to_speaker(&self) -> Vec<(SpeakerBox, Speaker)> {
+        let mut result = Vec::new();
+        for speaker in self.speakers.iter() {
+            log::debug!("Connecting to {} . . .", speaker.label());
+            if let Some(spk) = speaker.connect().await {
+                result.push((speaker.clone(), spk));
+                log::debug!("Successfully connected to {}.", speaker.label());
             } else {
-                log::debug!("Ignoring {}: Connection failed.", self.speaker.ip());
+                log::debug!("Ignoring {}: Connection failed.", speaker.label());
             }
+        }
         result
     }
 }
@@ -78,151 +159,886 @@ impl Config {
 #[derive(Debug, Clone)]
 struct AppState {
     username: String,
-    server: String,
     path: PathBuf,
     spk: Speaker,
-    tts_api_key: String,
+    members: Vec<Speaker>,
+    /// The members that joined the group of `spk` in the last take over
+    grouped: Vec<Speaker>,
+    /// Configuration of the coordinator and every member, in that order
+    boxes: Vec<SpeakerBox>,
+    tts: Vec<TtsBackend>,
+    speech: SpeechConfig,
+    templates: Templates,
+    fade_in: FadeConfig,
+    fade_out: FadeConfig,
     exec_interval: u16,
+    refresh_interval: u16,
+    music_duration: u32,
+    finish_track: bool,
+    listening_time: u32,
+    queue_mode: bool,
+    chime: String,
+    queue_tracks: u8,
+    stale_note: bool,
+    clock: SharedClock,
     fmstate: FreemindState,
-    jellyfin: JellyfinConfig,
+    music: Vec<MusicBackend>,
+    music_for: BTreeMap<String, String>,
+    music_ctx: MusicContext,
+    events: Option<EventListener>,
 }
 impl AppState {
     fn new(
-        username: String,
+        cfg: Config,
         server: String,
-        path: PathBuf,
-        spk: Speaker,
-        tts_api_key: String,
-        exec_interval: u16,
-        fmconf: FreemindConfig,
-        jellyfin: JellyfinConfig
-    ) -> Self {
-        Self {
-            username,
-            server,
-            path,
+        events: Option<EventListener>,
+        mut speakers: Vec<(SpeakerBox, Speaker)>,
+        clock: SharedClock,
+    ) -> Result<Self, AppError> {
+        let boxes = speakers.iter().map(|(b, _)| b.clone()).collect();
+        let (_, spk) = speakers.remove(0);
+        let members = speakers.into_iter().map(|(_, spk)| spk).collect();
+        let fmstate = freemind_state(&cfg, clock.clone());
+        let history = PlayHistory::load(state_file(&cfg, "history.json", "FreemindSonos.history.json"));
+        let templates = Templates::load(&cfg.path.join("templates"), cfg.speech.language())?;
+        let music_ctx = MusicContext {
+            root: cfg.path.clone(),
+            base_url: server,
+            jellyfin: cfg.jellyfin,
+            selection: cfg.selection,
+            history,
+            min_duration: cfg.fade_in.duration().as_secs(),
+            clock: clock.clone(),
+        };
+        Ok(Self {
+            username: cfg.username,
+            path: cfg.path,
             spk,
-            tts_api_key,
-            exec_interval,
-            fmstate: FreemindState::new(fmconf),
-            jellyfin,
-        }
+            members,
+            grouped: Vec::new(),
+            boxes,
+            tts: cfg.tts,
+            templates,
+            speech: cfg.speech,
+            fade_in: cfg.fade_in,
+            fade_out: cfg.fade_out,
+            exec_interval: cfg.exec_inverval,
+            refresh_interval: cfg.refresh_interval,
+            music_duration: cfg.music_duration,
+            finish_track: cfg.finish_track,
+            listening_time: cfg.listening_time,
+            queue_mode: cfg.queue_mode,
+            chime: cfg.chime,
+            queue_tracks: cfg.queue_tracks,
+            stale_note: cfg.stale_note,
+            clock,
+            fmstate,
+            music: cfg.music,
+            music_for: cfg.music_for,
+            music_ctx,
+            events,
+        })
     }
 
-    async fn fetch_tts_and_save(&self, txt: String) -> Result<(), reqwest::Error> {
-        let result = get_speech_voicerss(&txt, &self.tts_api_key).await?;
+    /// Speaks the text with the first working TTS engine and saves it in the media
+    /// directory. Returns the name of the written file.
+    async fn fetch_tts_and_save(&self, txt: Utterance) -> Result<String, AppError> {
+        let (result, engine) = synthesize_with_fallback(&self.tts, &txt, &self.speech).await?;
+        log::debug!("Generated speech using {}", engine);
 
-        let mut f = File::create(&self.path.join("tts.mp3")).unwrap();
-        f.write_all(&result).unwrap_or(());
+        let file = format!("tts.{}", engine.extension(&self.speech));
+        save_speech(&self.path.join(&file), &result)?;
 
+        Ok(file)
+    }
+
+    async fn play_uri(&self, uri: String, metadata: &str, play: bool) -> Result<(), AppError> {
+        self.spk.action(
+            AV_TRANSPORT,
+            "SetAVTransportURI",
+            args! {"InstanceID": "0", "CurrentURI": xml_escape(&uri), "CurrentURIMetaData": xml_escape(metadata)},
+        ).await?;
+
+        if play && !self.spk.is_playing().await.unwrap_or(false) {
+            self.spk.play().await?;
+        }
         Ok(())
     }
 
-    async fn play(&self) {
-        self.spk.play().await.unwrap();
+    /// The coordinator followed by every member that joined its group, the
+    /// speakers that play the announcement
+    fn speakers(&self) -> impl Iterator<Item = &Speaker> {
+        std::iter::once(&self.spk).chain(self.grouped.iter())
     }
 
-    async fn play_uri(&self, uri: String, play: bool) {
-        let result = self.spk.action(
-            AV_TRANSPORT,
-            "SetAVTransportURI",
-            args! {"InstanceID": "0", "CurrentURI": uri.as_str(), "CurrentURIMetaData": ""},
-        ).await;
+    /// The coordinator followed by every configured member, in the order of `boxes`
+    fn all_speakers(&self) -> impl Iterator<Item = &Speaker> {
+        std::iter::once(&self.spk).chain(self.members.iter())
+    }
 
-        if play && result.is_ok() && (!self.spk.is_playing().await.unwrap_or(false)) {
-            self.spk.play().await.unwrap()
+    /// Current volume of every speaker, in the order of `speakers()`
+    async fn volumes(&self) -> Vec<u16> {
+        let mut volumes = Vec::new();
+        for spk in self.speakers() {
+            volumes.push(spk.volume().await.unwrap_or(0));
         }
+        volumes
+    }
+
+    /// New events of the speakers, if the event listener is running
+    fn events(&self) -> Option<BoxStream<'static, SpeakerEvent>> {
+        self.events.as_ref().map(|e| e.events())
     }
 
-    async fn fade_in(&self) {
-        let current_volume = self.spk.volume().await.unwrap();
-        self.spk.set_volume(0).await.unwrap();
-        self.spk.play().await.unwrap();
-        while self.spk.set_volume_relative(3).await.unwrap_or(current_volume) < current_volume-3 {
-            sleep_until(Instant::now() + Duration::from_millis(500)).await;
+    /// Waits `dur` or until the transport state or track of the coordinator
+    /// changes. Without events this is a plain sleep.
+    async fn tick(&self, events: &mut Option<BoxStream<'static, SpeakerEvent>>, dur: Duration) -> Option<SpeakerEvent> {
+        let deadline = Instant::now() + dur;
+        let stream = match events {
+            Some(stream) => stream,
+            None => {
+                sleep_until(deadline).await;
+                return None;
+            }
+        };
+        let coordinator = speaker_ip(&self.spk).map(|ip| ip.to_string());
+        while let Ok(Some(event)) = timeout_at(deadline, stream.next()).await {
+            if coordinator.as_deref() == Some(event.speaker.as_str()) && event.is_transport_change() {
+                return Some(event);
+            }
         }
-        self.spk.set_volume(current_volume).await.unwrap();
+        None
     }
 
+    /// Changes the volume of every speaker from `from` to `to` following `fade`.
+    /// Returns false if `interrupted` matched an event of the coordinator first.
+    async fn run_fade(
+        &self,
+        from: &[u16],
+        to: &[u16],
+        fade: &FadeConfig,
+        mut interrupted: impl FnMut(&SpeakerEvent) -> bool,
+    ) -> bool {
+        let mut events = self.events();
+        let mut steps: Vec<(Duration, usize, u16)> = Vec::new();
+        for (idx, (from, to)) in from.iter().zip(to.iter()).enumerate() {
+            steps.extend(fade.schedule(*from, *to).into_iter().map(|(at, volume)| (at, idx, volume)));
+        }
+        steps.sort_by_key(|(at, idx, _)| (*at, *idx));
+
+        let start = Instant::now();
+        let speakers: Vec<&Speaker> = self.speakers().collect();
+        for (at, idx, volume) in steps {
+            while Instant::now() < start + at {
+                if let Some(event) = self.tick(&mut events, start + at - Instant::now()).await {
+                    if interrupted(&event) {
+                        return false;
+                    }
+                }
+            }
+            speakers[idx].set_volume(volume).await.unwrap_or(());
+        }
+        true
+    }
+
+    /// Starts playback and fades the music in. Returns false if someone paused
+    /// playback meanwhile.
+    async fn fade_in(&self) -> bool {
+        let targets = match self.fade_in.target() {
+            Some(volume) => vec![volume; self.grouped.len() + 1],
+            None => self.volumes().await,
+        };
+        for spk in self.speakers() {
+            spk.set_volume(0).await.unwrap_or(());
+        }
+        if let Err(err) = self.spk.play().await {
+            log::error!("Failed to start playback: {:?}", err);
+            for (spk, volume) in self.speakers().zip(targets) {
+                spk.set_volume(volume).await.unwrap_or(());
+            }
+            return false;
+        }
+
+        let mut playing = false;
+        let finished = self
+            .run_fade(&vec![0; targets.len()], &targets, &self.fade_in, |event| {
+                let paused = playing && event.is_paused();
+                playing |= event.transport_state() == Some("PLAYING");
+                paused
+            })
+            .await;
+        if !finished {
+            log::info!("Playback was paused during the fade in");
+        }
+        for (spk, volume) in self.speakers().zip(targets) {
+            spk.set_volume(volume).await.unwrap_or(());
+        }
+        finished
+    }
+
+    /// Fades the music out and pauses it, the volumes are reset afterwards
     async fn fade_out(&self) {
-        let current_volume = self.spk.volume().await.unwrap();
-        while self.spk.set_volume_relative(-3).await.unwrap_or(1) > 3 {
-            sleep_until(Instant::now() + Duration::from_millis(500)).await;
+        let volumes = self.volumes().await;
+        let finished = self
+            .run_fade(&volumes, &vec![0; volumes.len()], &self.fade_out, |event| event.is_stopped())
+            .await;
+        if !finished {
+            log::debug!("Playback stopped during the fade out");
+        }
+        self.spk.pause().await.unwrap_or(());
+        for (spk, volume) in self.speakers().zip(volumes) {
+            spk.set_volume(volume).await.unwrap_or(());
+        }
+    }
+
+    /// Waits until the current track ends, playback stops or `limit` has passed.
+    /// Streams have no length, so for them only stopping or the limit count.
+    async fn wait_for_end(&self, limit: Duration) {
+        let mut events = self.events();
+        let start = Instant::now();
+        let mut watch = TrackWatch::default();
+        let mut failures = 0;
+
+        let end = loop {
+            if start.elapsed() >= limit {
+                break None;
+            }
+            if let Some(event) = self.tick(&mut events, Duration::from_millis(500)).await {
+                if let Some(end) = watch.event(event.transport_state(), event.track_uri()) {
+                    break Some(end);
+                }
+            }
+
+            let pos = match position(&self.spk).await {
+                Ok(pos) => pos,
+                Err(err) => {
+                    failures += 1;
+                    log::debug!("Failed to get the playback position: {:?}", err);
+                    if failures > 10 {
+                        break None;
+                    }
+                    continue;
+                }
+            };
+            let playing = self.spk.is_playing().await.unwrap_or(false);
+            if let Some(end) = watch.observe(&pos, playing, start.elapsed()) {
+                break Some(end);
+            }
+        };
+
+        match end {
+            Some(TrackEnd::Ending(remaining)) => {
+                sleep_until(Instant::now() + Duration::from_secs(remaining as u64)).await;
+                log::debug!("Track ended");
+            }
+            Some(TrackEnd::Changed) => log::debug!("Track changed"),
+            Some(TrackEnd::Stopped) => log::debug!("Playback stopped"),
+            Some(TrackEnd::NotStarted) => log::warn!("Playback did not start"),
+            None => {}
         }
-        self.spk.pause().await.unwrap();
-        self.spk.set_volume(current_volume).await.unwrap();
     }
 
-    async fn wait_for_end(&self) {
-        while self.spk.is_playing().await.unwrap() {
-            sleep_until(Instant::now() + Duration::from_millis(500)).await;
+    /// Lets the wake-up music play for the configured duration, optionally until
+    /// the end of the track playing then. Returns false if someone paused it.
+    async fn wait_for_music(&self, started: Instant) -> bool {
+        let mut events = self.events();
+        let end = started + Duration::from_secs(self.music_duration as u64);
+        while Instant::now() < end {
+            if let Some(event) = self.tick(&mut events, end - Instant::now()).await {
+                if event.is_paused() {
+                    log::info!("The music was paused, skipping to the briefing");
+                    return false;
+                }
+            }
+        }
+        if self.finish_track {
+            self.wait_for_end(Duration::from_secs(15 * 60)).await;
         }
+        true
     }
 
-    async fn play_file(&self, file: String) {
-        let uri = format!("{}{}", self.server, file).replace(" ", "%20");
-        let result = self.spk.action(
-            AV_TRANSPORT,
-            "SetAVTransportURI",
-            args! {"InstanceID": "0", "CurrentURI": uri.as_str(), "CurrentURIMetaData": ""},
-        ).await;
+    /// URI and metadata of a file of the media directory, shown in the app as `title`
+    fn file_item(&self, file: &str, title: &str, description: Option<&str>) -> (String, String) {
+        let uri = self.music_ctx.file_url(file);
+        let metadata = DidlItem::new(&uri, title)
+            .class(AUDIO_ITEM)
+            .mime(mime_type(Path::new(file)))
+            .artist(Some(&self.username))
+            .description(description)
+            .build();
+        (uri, metadata)
+    }
+
+    async fn play_file(&self, file: String, title: &str, description: Option<&str>) -> Result<(), AppError> {
+        let (uri, metadata) = self.file_item(&file, title, description);
+        self.play_uri(uri, &metadata, true).await
+    }
+
+    /// Remembers the state of every speaker, then prepares and groups them for
+    /// an announcement. Members that fail to join are left out of `speakers()`.
+    async fn take_over(&mut self) -> Vec<SpeakerSnapshot> {
+        let mut snapshots = Vec::new();
+        for spk in self.all_speakers() {
+            snapshots.push(SpeakerSnapshot::take(spk).await);
+        }
+        for (spk, speaker_box) in self.all_speakers().zip(self.boxes.iter()) {
+            speaker_box.prepare(spk).await;
+        }
+        self.grouped = group_speakers(&self.spk, self.members.clone()).await;
+        snapshots
+    }
+
+    /// Puts every speaker back into the state it was in before `take_over`
+    async fn restore(&self, snapshots: Vec<SpeakerSnapshot>) {
+        for (spk, snapshot) in self.all_speakers().zip(snapshots.iter()) {
+            snapshot.restore(spk).await;
+        }
+        log::info!("Restored the previous state of {} speakers", snapshots.len());
+    }
+
+    /// Takes over the speakers, plays the briefing for `alerts` and restores what
+    /// was playing before. Alerts another run is already announcing are left out.
+    async fn announce(&mut self, alerts: &[(u16, u32)]) -> Result<(), AppError> {
+        let alerts = self.fmstate.reserve(alerts);
+        if alerts.is_empty() {
+            log::info!("The alerts were already announced or another run is announcing them");
+            return Ok(());
+        }
+        let source = music_source(&self.music_for, &self.fmstate, &alerts).map(|s| s.to_string());
+        let snapshots = self.take_over().await;
+        let result = self.briefing(source.as_deref()).await;
+        match result {
+            Ok(()) => {
+                self.fmstate.mark_announced(&alerts);
+                self.fmstate.acknowledge_alerts(&alerts).await;
+            }
+            Err(_) => self.fmstate.release(&alerts),
+        }
+        self.restore(snapshots).await;
+        result
+    }
+
+    /// Picks up to `count` different tracks from the music source named `source`,
+    /// fetching its tracks only once. Live streams never end, so `queued` leaves
+    /// them out.
+    async fn pick_tracks(&self, source: Option<&str>, count: usize, queued: bool) -> Vec<Track> {
+        let backend = match select_source(&self.music, source) {
+            Some(val) => val,
+            None => return Vec::new(),
+        };
+        let mut tracks = match backend.tracks(&self.music_ctx).await {
+            Ok(val) => val,
+            Err(err) => {
+                log::error!("Failed to get tracks from {}: {}", backend, err);
+                return Vec::new();
+            }
+        };
+        if queued {
+            let available = tracks.len();
+            tracks.retain(|t| t.resume != Resume::Live);
+            if tracks.len() < available {
+                log::info!("Leaving out {} live streams of {} in queue mode", available - tracks.len(), backend);
+            }
+        }
+        let picked = self.music_ctx.select(tracks, count);
+        log::debug!("Picked {:?} from {}", picked.iter().map(|t| &t.title).collect::<Vec<_>>(), backend);
+        picked
+    }
+
+    /// Renders and speaks the briefing of today's events. Returns the file, its
+    /// title and a summary of the events.
+    async fn speak_briefing(&mut self) -> Result<(String, String, String), AppError> {
+        let stale = self.stale_note && self.fmstate.is_stale();
+        let elements = self.fmstate.get_today();
+
+        let message = self
+            .templates
+            .render_wake_up(&self.clock.now(), &BriefingContext::new(&self.username, &elements, stale))?;
+        let title = format!("Briefing {}", self.clock.now().format("%Y-%m-%d"));
+        let summary = elements.iter().map(|(e, _)| e.title()).collect::<Vec<_>>().join(", ");
+
+        let tts_file = self.fetch_tts_and_save(message).await?;
+        Ok((tts_file, title, summary))
+    }
+
+    /// Plays the wake-up music followed by the spoken briefing of today's events.
+    /// Music that fails to play is skipped, the briefing itself has to succeed.
+    async fn briefing(&mut self, source: Option<&str>) -> Result<(), AppError> {
+        if self.queue_mode {
+            return self.queue_briefing(source).await;
+        }
+        let track = self.pick_tracks(source, 1, false).await.pop();
+        if let Some(track) = &track {
+            self.music_ctx.played(track);
+        }
+
+        let music_start = Instant::now();
+        let mut music = track.is_some();
+        if let Some(track) = &track {
+            music = match self.play_uri(track.uri.clone(), &track.metadata(), false).await {
+                Ok(()) => self.fade_in().await,
+                Err(err) => {
+                    log::error!("Failed to play {}: {}", track.title, err);
+                    false
+                }
+            };
+        };
+
+        let (tts_file, title, summary) = self.speak_briefing().await?;
+        let mut stopped_at = None;
+        if music && self.wait_for_music(music_start).await {
+            // The music keeps playing while it fades out, so it continues from where the fade ended
+            self.fade_out().await;
+            stopped_at = match position(&self.spk).await {
+                Ok(pos) => Some(pos.elapsed as u64),
+                Err(_) => Some(music_start.elapsed().as_secs()),
+            };
+        }
+
+        self.play_file(tts_file, &title, (!summary.is_empty()).then_some(summary.as_str())).await?;
+        self.wait_for_end(Duration::from_secs(10 * 60)).await;
+
+        if let (Some(track), Some(offset)) = (&track, stopped_at) {
+            let played = music_start.elapsed().as_secs().min(offset);
+            self.resume_music(track, offset, played).await;
+        }
+        Ok(())
+    }
+
+    /// Plays the chime, the briefing and then music as one queue
+    async fn queue_briefing(&mut self, source: Option<&str>) -> Result<(), AppError> {
+        let (tts_file, title, summary) = self.speak_briefing().await?;
+
+        let mut queue = QueueBuilder::new();
+        if !self.chime.is_empty() {
+            let (uri, metadata) = self.file_item(&self.chime, "Chime", None);
+            queue = queue.add(&uri, &metadata);
+        }
+        let (uri, metadata) = self.file_item(&tts_file, &title, (!summary.is_empty()).then_some(summary.as_str()));
+        queue = queue.add(&uri, &metadata);
+        let spoken = queue.len();
+
+        for track in self.pick_tracks(source, self.queue_tracks as usize, true).await {
+            self.music_ctx.played(&track);
+            queue = queue.add(&track.uri, &track.metadata());
+        }
+
+        queue.load(&self.spk).await?;
+        self.spk.play().await?;
+        for _ in 0..spoken {
+            self.wait_for_end(Duration::from_secs(10 * 60)).await;
+        }
+        if queue.len() == spoken {
+            return Ok(());
+        }
+
+        let end = (self.listening_time > 0).then(|| Instant::now() + Duration::from_secs(self.listening_time as u64));
+        while self.spk.is_playing().await.unwrap_or(false) {
+            match end {
+                Some(end) if Instant::now() >= end => {
+                    self.fade_out().await;
+                    break;
+                }
+                Some(end) => self.wait_for_end(end - Instant::now()).await,
+                None => self.wait_for_end(Duration::from_secs(15 * 60)).await,
+            }
+        }
+        Ok(())
+    }
+
+    /// Continues `track` at `offset` seconds until the listening time is used up,
+    /// `played` seconds of it were already played before the briefing
+    async fn resume_music(&self, track: &Track, offset: u64, played: u64) {
+        let remaining = (self.listening_time as u64).saturating_sub(played);
+        if remaining == 0 {
+            return;
+        }
+        // Resuming for the last few seconds of a track is not worth it
+        if track.duration.is_some_and(|d| offset + 5 >= d) {
+            log::debug!("The track already ended, not resuming it");
+            return;
+        }
+
+        log::info!("Resuming {} at {} seconds for {} seconds", track.title, offset, remaining);
+        let started = Instant::now();
+        if let Err(err) = self.play_uri(track.uri_at(offset), &track.metadata(), false).await {
+            log::error!("Failed to resume {}: {}", track.title, err);
+            return;
+        }
+        if track.resume == Resume::Seek && offset > 0 {
+            let target = format!("{}:{:02}:{:02}", offset / 3600, offset / 60 % 60, offset % 60);
+            self.spk
+                .action(AV_TRANSPORT, "Seek", args! {"InstanceID": "0", "Unit": "REL_TIME", "Target": target.as_str()})
+                .await
+                .unwrap_or_else(|err| {
+                    log::warn!("Failed to seek to {}: {:?}", target, err);
+                    Default::default()
+                });
+        }
+        if !self.fade_in().await {
+            return;
+        }
+        self.wait_for_end(Duration::from_secs(remaining).saturating_sub(started.elapsed())).await;
+        if self.spk.is_playing().await.unwrap_or(false) {
+            self.fade_out().await;
+        }
+    }
+
+    /// Announces the alerts due within the next execution interval, if any
+    async fn trigger_due(&mut self) -> Result<(), AppError> {
+        self.fmstate.fetch().await?;
 
-        if result.is_ok() && (!self.spk.is_playing().await.unwrap_or(false)) {
-            self.spk.play().await.unwrap()
+        let alerts = self.fmstate.needs_trigger(self.exec_interval);
+        if alerts.is_empty() {
+            log::info!("No events instructed to trigger");
+            return Ok(());
+        };
+
+        self.announce(&alerts).await
+    }
+
+    /// Keeps running, refreshing the registry every `refresh_interval` minutes and
+    /// playing the briefing exactly when an alert is due. Only returns on failures
+    /// that retrying won't fix.
+    async fn daemon(&mut self) -> Result<(), AppError> {
+        let refresh = Duration::from_secs(self.refresh_interval as u64 * 60);
+        let mut last_fetch: Option<Instant> = None;
+
+        loop {
+            if last_fetch.is_none_or(|t| t.elapsed() >= refresh) {
+                if let Err(err) = self.fmstate.fetch().await {
+                    let err = AppError::from(err);
+                    if err.is_permanent() {
+                        return Err(err);
+                    }
+                    log::error!("Failed to refresh the registry: {}", err);
+                }
+                last_fetch = Some(Instant::now());
+            }
+            let next_refresh = last_fetch.unwrap_or_else(Instant::now) + refresh;
+
+            self.fmstate.reload_announced();
+            let now = self.clock.unix();
+            match self.fmstate.next_alert(now) {
+                Some(at) if Instant::now() + Duration::from_secs(at.saturating_sub(now) as u64) <= next_refresh => {
+                    log::info!("Next alert in {} seconds", at.saturating_sub(now));
+                    sleep_until(Instant::now() + Duration::from_secs(at.saturating_sub(now) as u64)).await;
+                    let alerts = self.fmstate.due_alerts(at + 1);
+                    if !alerts.is_empty() {
+                        if let Err(err) = self.announce(&alerts).await {
+                            if err.is_permanent() {
+                                return Err(err);
+                            }
+                            log::error!("Failed to announce, retrying in {} seconds: {}", RETRY_DELAY.as_secs(), err);
+                            sleep(RETRY_DELAY).await;
+                        }
+                    }
+                }
+                _ => {
+                    log::debug!("No alert before the next refresh");
+                    sleep_until(next_refresh).await;
+                }
+            }
         }
     }
 }
 
-async fn init<'a>(log_level: LevelFilter) -> AppState {
-    Builder::new().filter(None, log_level).init();
+impl Config {
+    /// Moves the deprecated `tts_api_key` into the first VoiceRSS backend, adding
+    /// one in front if there is none
+    fn migrate_tts_api_key(&mut self) {
+        let key = match self.tts_api_key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        log::warn!("`tts_api_key` is deprecated, configure a VoiceRSS backend in `tts` instead");
+        match self.tts.iter_mut().find_map(|backend| match backend {
+            TtsBackend::VoiceRss { api_key, .. } => Some(api_key),
+            _ => None,
+        }) {
+            Some(api_key) => *api_key = key,
+            None => self.tts.insert(0, TtsBackend::VoiceRss { api_key: key, ssml: false }),
+        }
+    }
+}
+
+/// Writes generated speech to `path`
+fn save_speech(path: &Path, speech: &[u8]) -> Result<(), AppError> {
+    std::fs::write(path, speech)
+        .map_err(|err| AppError::Speech(format!("Failed to write the speech to {}: {}", path.display(), err)))
+}
+
+/// The music source `music_for` names for the first of `alerts` that has one
+fn music_source<'m>(music_for: &'m BTreeMap<String, String>, fmstate: &FreemindState, alerts: &[(u16, u32)]) -> Option<&'m str> {
+    alerts
+        .iter()
+        .filter_map(|(id, _)| fmstate.element(*id))
+        .find_map(|e| music_for.get(e.title()))
+        .map(|s| s.as_str())
+}
+
+fn load_config() -> Result<Config, AppError> {
     log::info!("Initializing . . .");
 
     log::debug!("Loading Config . . .");
-    let cfg: Config = confy::load_path("./FreemindSonos.config").expect(
-        "Failed to start because the config file could not be created or could not be read!",
-    );
-    let path = cfg.path.clone().into_boxed_path();
-    if !path.exists() {
-        panic!("Provided path in the config does not exist!")
-    } else if !path.is_dir() {
-        panic!("Provided path in the config is not an directory!")
+    let mut cfg: Config = confy::load_path(CONFIG_FILE)?;
+    cfg.migrate_tts_api_key();
+    if !cfg.path.exists() {
+        return Err(AppError::Config("Provided path in the config does not exist!".to_string()));
+    } else if !cfg.path.is_dir() {
+        return Err(AppError::Config("Provided path in the config is not an directory!".to_string()));
+    }
+    Ok(cfg)
+}
+
+/// The directory of the config file. Private state like the registry cache, the
+/// announcement record and the play history is kept there rather than in the
+/// media directory, which is served to the speakers.
+fn config_dir() -> PathBuf {
+    Path::new(CONFIG_FILE).parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// The file `name` in the config directory. Earlier versions kept it in the
+/// media directory as `old`, from where it is moved on first use.
+fn state_file(cfg: &Config, old: &str, name: &str) -> PathBuf {
+    let path = config_dir().join(name);
+    let old = cfg.path.join(old);
+    if !path.exists() && old.is_file() {
+        match std::fs::rename(&old, &path) {
+            Ok(()) => log::info!("Moved {} to {}", old.display(), path.display()),
+            Err(err) => log::warn!("Failed to move {} to {}: {}", old.display(), path.display(), err),
+        }
     }
+    path
+}
+
+/// Like `state_file`, but reads a file left in the old place from there
+/// instead of moving it
+fn existing_state_file(cfg: &Config, old: &str, name: &str) -> PathBuf {
+    let path = config_dir().join(name);
+    let old = cfg.path.join(old);
+    if !path.exists() && old.is_file() {
+        old
+    } else {
+        path
+    }
+}
+
+fn freemind_state(cfg: &Config, clock: SharedClock) -> FreemindState {
+    FreemindState::new(
+        cfg.freemind.clone(),
+        state_file(cfg, "announced.json", "FreemindSonos.announced.json"),
+        config_dir().join("FreemindSonos.registry.json"),
+        cfg.grace_period,
+        clock,
+    )
+}
+
+/// A Freemind state that neither moves old files nor writes the registry cache
+fn read_only_freemind_state(cfg: &Config, clock: SharedClock) -> FreemindState {
+    FreemindState::new(
+        cfg.freemind.clone(),
+        existing_state_file(cfg, "announced.json", "FreemindSonos.announced.json"),
+        config_dir().join("FreemindSonos.registry.json"),
+        cfg.grace_period,
+        clock,
+    )
+    .read_only()
+}
+
+/// Works on the Freemind registry without touching any speaker
+async fn registry(clock: SharedClock, command: &str, id: Option<u16>) -> Result<(), AppError> {
+    let cfg = load_config()?;
+    let mut fmstate = freemind_state(&cfg, clock);
+    match (command, id) {
+        ("acknowledge", Some(id)) => fmstate.acknowledge(id).await?,
+        ("delete", Some(id)) => fmstate.delete(&[id]).await?,
+        ("due", _) => fmstate.due_today().await?.iter().for_each(|e| match e.id() {
+            Some(id) => println!("{}: {}", id, e.title()),
+            None => println!("{}", e.title()),
+        }),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Adds `element` to the Freemind registry and prints the id it got
+async fn create(clock: SharedClock, element: AppElement) -> Result<(), AppError> {
+    let cfg = load_config()?;
+    let mut fmstate = freemind_state(&cfg, clock);
+    if let Some(id) = fmstate.create(element).await? {
+        println!("{}", id);
+    }
+    Ok(())
+}
+
+/// Collects the IPv4 networks of every interface that is up and not a loopback
+fn local_networks() -> Result<Vec<Ipv4Network>, AppError> {
     log::debug!("Getting IP Addresses of the machine");
-    let mut addrs: Vec<Ipv4Addr> = Vec::new();
+    let mut addrs: Vec<Ipv4Network> = Vec::new();
     for iface in interfaces()
         .iter()
         .filter(|e| e.is_up() && !e.is_loopback() && !e.ips.is_empty())
     {
-        let mut ips: Vec<Ipv4Addr> = Vec::new();
         for ip in iface.ips.iter() {
             if let ipnetwork::IpNetwork::V4(addr) = ip {
-                ips.push(addr.ip())
+                addrs.push(*addr)
             }
         }
-        addrs.append(&mut ips)
     }
     if addrs.is_empty() {
-        panic!("This machine does not have any IPv4 Address. Please make sure that all desired network-interfaces are connected to a network, have a valid IPv4 address and are accessible by this program");
+        return Err(AppError::Network("This machine does not have any IPv4 Address. Please make sure that all desired network-interfaces are connected to a network, have a valid IPv4 address and are accessible by this program".to_string()));
     } else {
         log::info!("Found {} IP addresses", addrs.len());
         log::debug!("These IP addresses were found:\n{:#?}", addrs);
     }
+    Ok(addrs)
+}
 
-    log::debug!("Trying to connect to configured speaker . . .");
-    AppState::new(
-        cfg.username.clone(),
-        cfg.local_server.clone(),
-        path.to_path_buf(),
-        cfg.to_speaker().await.unwrap(),
-        cfg.tts_api_key,
-        cfg.exec_inverval,
-        cfg.freemind,
-        cfg.jellyfin,
-    )
+/// Starts the built-in media server if enabled and returns the base URL under
+/// which the speaker can reach the media directory.
+fn start_media_server(cfg: &Config, networks: &[Ipv4Network], speaker: Option<Ipv4Addr>) -> Result<String, AppError> {
+    if !cfg.media_server.enabled() {
+        log::debug!("Built-in media server disabled, using {}", cfg.local_server);
+        return Ok(cfg.local_server.clone());
+    }
+    let addr = cfg
+        .media_server
+        .select_address(networks, speaker)
+        .ok_or_else(|| AppError::Config("No address available for the media server".to_string()))?;
+    let server = MediaServer::new(cfg.path.clone(), addr, cfg.media_server.port());
+    server.spawn()?;
+    Ok(server.base_url())
+}
+
+/// Starts receiving events if enabled and subscribes to every speaker. Without
+/// events everything falls back to polling.
+async fn start_event_listener(
+    cfg: &Config,
+    networks: &[Ipv4Network],
+    speakers: &[(SpeakerBox, Speaker)],
+) -> Option<EventListener> {
+    if !cfg.events.enabled() {
+        log::debug!("Speaker events disabled");
+        return None;
+    }
+    let addr = cfg
+        .media_server
+        .select_address(networks, speakers.first().and_then(|(_, spk)| speaker_ip(spk)))?;
+    let listener = match EventListener::spawn(SocketAddr::from((addr, cfg.events.port()))) {
+        Ok(val) => val,
+        Err(err) => {
+            log::error!("Failed to start the event listener: {}", err);
+            return None;
+        }
+    };
+    for (speaker_box, spk) in speakers {
+        if let Err(err) = listener.subscribe(spk).await {
+            log::warn!("Failed to subscribe to events of {}: {}", speaker_box.label(), err);
+        }
+    }
+    Some(listener)
+}
+
+async fn init(clock: SharedClock) -> Result<AppState, AppError> {
+    let cfg = load_config()?;
+    let networks = local_networks()?;
+
+    log::debug!("Trying to connect to configured speakers . . .");
+    let speakers = cfg.to_speaker().await;
+    let (_, spk) = speakers
+        .first()
+        .ok_or_else(|| AppError::Speaker("Could not connect to any of the configured speakers".to_string()))?;
+    let server = start_media_server(&cfg, &networks, speaker_ip(spk))?;
+    let events = start_event_listener(&cfg, &networks, &speakers).await;
+    AppState::new(cfg, server, events, speakers, clock)
+}
+
+/// Only runs the media server, useful to check it with a regular HTTP client
+async fn serve() -> Result<(), AppError> {
+    let cfg = load_config()?;
+    let networks = local_networks()?;
+    let addr = cfg
+        .media_server
+        .select_address(&networks, cfg.speakers.first().and_then(|s| s.ip()))
+        .ok_or_else(|| AppError::Config("No address available for the media server".to_string()))?;
+    let server = MediaServer::new(cfg.path.clone(), addr, cfg.media_server.port());
+    server.spawn()?.await.unwrap_or(());
+    Ok(())
+}
+
+/// Local date and time of a unix timestamp
+fn format_time(at: u32) -> String {
+    chrono::Local
+        .timestamp_opt(at as i64, 0)
+        .earliest()
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| at.to_string())
+}
+
+/// Shows what a run at this time would do without touching any speaker: today's
+/// events, the alerts that would go off and the briefing. The speech is only
+/// generated if `tts_file` is given, and written there.
+async fn preview(clock: SharedClock, tts_file: Option<&str>) -> Result<(), AppError> {
+    let cfg = load_config()?;
+    // Only looks, nothing on disk changes
+    let mut fmstate = read_only_freemind_state(&cfg, clock.clone());
+    fmstate.fetch().await?;
+
+    println!("Now: {}", clock.now().format("%Y-%m-%d %H:%M"));
+    if let Some(fetched) = fmstate.fetched_at() {
+        let stale = if fmstate.is_stale() { " (server unreachable)" } else { "" };
+        println!("Registry from {}{}", fetched.format("%Y-%m-%d %H:%M"), stale);
+    }
+
+    let today = fmstate.get_today();
+    println!("\nToday:");
+    if today.is_empty() {
+        println!("  nothing");
+    }
+    for (element, occurrence) in today.iter() {
+        let id = element.id().unwrap_or(0);
+        print!("  {} [{}] {}", format_time(occurrence.event), id, element.title());
+        if let Some(alert) = fmstate.alert(id) {
+            let announced = if fmstate.is_announced(id, occurrence.alert) { ", announced" } else { "" };
+            print!(" (alert '{}' at {}{})", alert, format_time(occurrence.alert), announced);
+        }
+        println!();
+    }
+
+    let alerts = fmstate.needs_trigger(cfg.exec_inverval);
+    println!("\nAlerts within the next {} minutes:", cfg.exec_inverval);
+    if alerts.is_empty() {
+        println!("  none");
+    }
+    for (id, at) in alerts.iter() {
+        let title = fmstate.element(*id).map(|e| e.title()).unwrap_or_default();
+        let source = select_source(&cfg.music, music_source(&cfg.music_for, &fmstate, &[(*id, *at)])).map(|s| s.name().to_string());
+        println!("  {} [{}] {} with music from {}", format_time(*at), id, title, source.unwrap_or_default());
+    }
+
+    let templates = Templates::load(&cfg.path.join("templates"), cfg.speech.language())?;
+    let stale = cfg.stale_note && fmstate.is_stale();
+    let message = templates.render_wake_up(&clock.now(), &BriefingContext::new(&cfg.username, &today, stale))?;
+    println!("\nBriefing:\n{}", message.plain);
+
+    if let Some(file) = tts_file {
+        let (speech, engine) = synthesize_with_fallback(&cfg.tts, &message, &cfg.speech).await?;
+        save_speech(Path::new(file), &speech)?;
+        println!("\nSpeech by {} written to {}", engine, file);
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("Error: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+async fn run() -> Result<(), AppError> {
     let args = Command::new("Sonos Controller")
         .version("0.1.0")
         .author("doncato, https://github.com/doncato")
@@ -232,6 +1048,70 @@ async fn main() {
                 .long("debug")
                 .help("Change log level to debug"),
         )
+        .arg(
+            Arg::new("now")
+                .long("now")
+                .takes_value(true)
+                .value_name("TIME")
+                .help("With preview only, act as if it was this time, as 'YYYY-MM-DD HH:MM' or RFC 3339"),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Only run the built-in media server"),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("List the speakers found in the network"),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Keep running and trigger alerts on time instead of checking once"),
+        )
+        .subcommand(
+            Command::new("acknowledge")
+                .about("Mark the alert of an element as done")
+                .arg(Arg::new("id").required(true).help("Id of the element")),
+        )
+        .subcommand(
+            Command::new("create")
+                .about("Add an element to the registry")
+                .arg(Arg::new("title").required(true).help("Name of the element"))
+                .arg(Arg::new("description").help("What the element is about"))
+                .arg(
+                    Arg::new("due")
+                        .long("due")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("When the element is due, as 'YYYY-MM-DD HH:MM' or RFC 3339"),
+                )
+                .arg(
+                    Arg::new("alert")
+                        .long("alert")
+                        .takes_value(true)
+                        .value_name("ALERT")
+                        .help("Alert to announce the element with"),
+                ),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete an element from the registry")
+                .arg(Arg::new("id").required(true).help("Id of the element")),
+        )
+        .subcommand(
+            Command::new("preview")
+                .about("Show today's events, due alerts and the briefing without using any speaker")
+                .arg(
+                    Arg::new("tts")
+                        .long("tts")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Also generate the speech and write it to this file"),
+                ),
+        )
+        .subcommand(
+            Command::new("due")
+                .about("List the elements the registry considers due today"),
+        )
         .get_matches();
 
     let llvl = if args.is_present("debug") {
@@ -239,71 +1119,163 @@ async fn main() {
     } else {
         LevelFilter::Info
     };
-    let mut op: AppState = init(llvl).await;
+    Builder::new().filter(None, llvl).init();
+    // A simulated time must not play anything or record announcements, so it
+    // is only used by preview, which just shows what would happen. `due` asks the
+    // server, which always answers for the real day.
+    let clock: SharedClock = match args.value_of("now") {
+        Some(now) if args.subcommand_name() != Some("preview") => {
+            return Err(AppError::Config(format!("--now {} only works with preview", now)))
+        }
+        Some(now) => match parse_time(now) {
+            Some(at) => Arc::new(FixedClock(at)),
+            None => return Err(AppError::Config(format!("Can't read the time '{}'", now))),
+        },
+        None => system_clock(),
+    };
+    if args.subcommand_matches("serve").is_some() {
+        return serve().await;
+    }
+    if let Some(sub) = args.subcommand_matches("preview") {
+        return preview(clock, sub.value_of("tts")).await;
+    }
+    if let Some(sub) = args.subcommand_matches("create") {
+        let due = match sub.value_of("due").map(|due| (due, parse_time(due))) {
+            Some((_, Some(at))) => Some(at.timestamp().try_into().unwrap_or(0)),
+            Some((due, None)) => return Err(AppError::Config(format!("Can't read the time '{}'", due))),
+            None => None,
+        };
+        let mut element = AppElement::new(sub.value_of("title").unwrap(), sub.value_of("description").unwrap_or_default(), due);
+        element.set_alert(sub.value_of("alert").map(str::to_string));
+        return create(clock, element).await;
+    }
+    if let Some((command @ ("acknowledge" | "delete" | "due"), sub)) = args.subcommand() {
+        let id = match sub.value_of("id").map(|id| id.parse::<u16>()) {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Err(AppError::Config("The id has to be a number".to_string())),
+            None => None,
+        };
+        return registry(clock, command, id).await;
+    }
+    if args.subcommand_matches("discover").is_some() {
+        match discover_speakers().await {
+            Ok(speakers) if speakers.is_empty() => println!("No speakers found"),
+            Ok(speakers) => speakers.iter().for_each(|(room, ip)| match ip {
+                Some(ip) => println!("{} ({})", room, ip),
+                None => println!("{}", room),
+            }),
+            Err(err) => return Err(err.into()),
+        }
+        return Ok(());
+    }
+    let mut op: AppState = init(clock).await?;
     log::info!("Initialized.");
-    log::debug!("Connected to {:#?} Speaker", op.spk);
-    
-    op.fmstate.fetch().await.unwrap();
+    log::debug!("Connected to {:#?} Speaker with {} grouped", op.spk, op.members.len());
 
-    if !op.fmstate.needs_trigger(op.exec_interval) {
-        log::info!("No events instructed to trigger");
-        return;
+    let result = match args.subcommand_matches("daemon") {
+        Some(_) => op.daemon().await,
+        None => op.trigger_due().await,
     };
+    if let Some(events) = &op.events {
+        events.unsubscribe_all().await;
+    }
+    result
+}
 
-    let track = get_random_jellyfin_track(&op.jellyfin).await.unwrap();//_or(None)
-
-    if let Some(title) = &track {
-        op.play_uri(format!("https://venture.zossennews.de/media/Audio/{}/stream.mp3", title.id).to_string(), false).await;
-        op.fade_in().await
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::speech::Language;
 
+    fn voicerss_keys(cfg: &Config) -> Vec<Option<&str>> {
+        cfg.tts
+            .iter()
+            .map(|backend| match backend {
+                TtsBackend::VoiceRss { api_key, .. } => Some(api_key.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
 
-    let elements = op.fmstate.get_today();
-    let count = elements.len();
+    fn espeak() -> TtsBackend {
+        TtsBackend::Command {
+            program: "espeak-ng".to_string(),
+            args: vec!["--stdout".to_string()],
+            extension: "wav".to_string(),
+            ssml: false,
+        }
+    }
 
-    let mut i: u8 = 0;
-    let mut event_list = String::new();
-    elements.iter().for_each(|e| {
-        i+=1;
-        event_list.push_str(format!("Number {}: {}.\n ", i, e.description()).as_str());
+    #[test]
+    fn moves_the_old_key_into_the_voicerss_backend() {
+        let mut cfg: Config = serde_json::from_str(r#"{"tts_api_key": "old key"}"#).unwrap();
+        cfg.tts = vec![espeak(), TtsBackend::default()];
+        cfg.migrate_tts_api_key();
+        assert_eq!(voicerss_keys(&cfg), vec![None, Some("old key")]);
+        assert!(cfg.tts_api_key.is_none());
+    }
 
-        let timepoint = e.timepoint();
+    #[test]
+    fn adds_a_voicerss_backend_for_the_old_key() {
+        let mut cfg: Config = serde_json::from_str(r#"{"tts_api_key": "old key"}"#).unwrap();
+        cfg.tts = vec![espeak()];
+        cfg.migrate_tts_api_key();
+        assert_eq!(voicerss_keys(&cfg), vec![Some("old key"), None]);
+        assert!(!cfg.to_string().contains("tts_api_key"));
+    }
 
-        if e.location() != "" || timepoint.is_some() {
-            event_list.push_str("Taking place");
-            if e.location() != "" {
-                event_list.push_str(format!(" at {}", e.location()).as_str());
-            }
-            if timepoint.is_some() {
-                event_list.push_str(format!(" at {}", timepoint.unwrap()).as_str());
-            }
-        }
-        event_list.push_str(".\n ");
-    });
+    #[test]
+    fn keeps_configs_without_the_old_key() {
+        let mut cfg = Config { tts: vec![espeak()], ..Config::default() };
+        cfg.migrate_tts_api_key();
+        assert_eq!(voicerss_keys(&cfg), vec![None]);
+    }
 
-    let message = format!(
-        "Hey {}! You have {} events due today.\n {}",
-        op.username,
-        count,
-        event_list,
-    );
+    /// Loads `toml` from a config file named `name` the way `load_config` does
+    fn load_toml(name: &str, toml: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("freemind-sonos-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let cfg = confy::load_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        cfg.unwrap()
+    }
 
-    op.fetch_tts_and_save(message).await.unwrap();
-    sleep_until(Instant::now() + Duration::from_secs(120)).await;
-    //op.wait_for_end().await;
+    #[test]
+    fn fills_in_missing_speech_settings() {
+        let cfg = load_toml("speech", "[speech]\nlanguage = \"de-de\"\n");
+        assert_eq!(cfg.speech.language(), Language::German);
+        assert!(cfg.speech.to_string().contains(r#""voice":"Nancy""#));
+    }
 
-    op.fade_out().await;
+    #[test]
+    fn fills_in_missing_fade_settings() {
+        let cfg = load_toml("fade", "[fade_in]\nduration = 5\n\n[fade_out]\ntarget = 20\n");
+        assert_eq!(cfg.fade_in.duration(), Duration::from_secs(5));
+        assert_eq!(cfg.fade_in.target(), None);
+        assert_eq!(cfg.fade_out.duration(), Duration::from_secs(10));
+        assert_eq!(cfg.fade_out.target(), Some(20));
+    }
 
-    op.play_file("tts.mp3".to_string()).await;
+    #[test]
+    fn fills_in_missing_selection_settings() {
+        let cfg = load_toml("selection", "[selection]\nstrategy = \"weighted\"\n");
+        assert_eq!(
+            cfg.selection.to_string(),
+            r#"{"strategy":"weighted","avoid_days":3,"favourite_weight":3,"seed":null}"#
+        );
+    }
 
-    /*
-    sleep_until(Instant::now() + Duration::from_millis(500)).await;
-    op.wait_for_end().await;
+    #[test]
+    fn fills_in_missing_media_server_settings() {
+        let cfg = load_toml("media-server", "[media_server]\nport = 8400\n");
+        assert!(cfg.media_server.enabled());
+        assert_eq!(cfg.media_server.port(), 8400);
+    }
 
-    if let Some(title) = &track {
-        op.play_uri(format!("https://venture.zossennews.de/media/Audio/{}/stream.mp3?startTimeTicks=1200000000", title.id).to_string(), false).await;
-        op.play().await;
-        op.fade_in().await;
-    };
-    */
+    #[test]
+    fn fills_in_missing_event_settings() {
+        let cfg = load_toml("events", "[events]\nenabled = false\n");
+        assert!(!cfg.events.enabled());
+        assert_eq!(cfg.events.port(), 8322);
+    }
 }
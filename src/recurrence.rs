@@ -0,0 +1,175 @@
+pub mod recurrence_rule {
+    use chrono::{Local, TimeZone};
+    use cron::Schedule;
+    use std::str::FromStr;
+
+    /// When an element takes place: once at `start`, or every time `rule` matches
+    /// from `start` until and including `end`, leaving out the `exceptions`.
+    /// All times are unix timestamps, rules are evaluated in local time.
+    #[derive(Debug, Clone)]
+    pub struct Recurrence {
+        start: Option<u32>,
+        rule: Option<Schedule>,
+        end: Option<u32>,
+        exceptions: Vec<u32>,
+    }
+
+    impl Recurrence {
+        /// An invalid `rule` is logged and ignored, the element then only takes place at `start`
+        pub fn new(start: Option<u32>, rule: Option<&str>, end: Option<u32>, exceptions: &[u32]) -> Self {
+            let rule = rule.and_then(|r| match Schedule::from_str(r) {
+                Ok(val) => Some(val),
+                Err(err) => {
+                    log::warn!("Ignoring invalid repetition '{}': {}", r, err);
+                    None
+                }
+            });
+            Self {
+                start,
+                rule,
+                end,
+                exceptions: exceptions.to_vec(),
+            }
+        }
+
+        /// Every time it takes place at or after `from`, in order
+        pub fn starting_at(&self, from: u32) -> Box<dyn Iterator<Item = u32> + '_> {
+            let from = from.max(self.start.unwrap_or(0));
+            let end = self.end.unwrap_or(u32::MAX);
+            let times: Box<dyn Iterator<Item = u32> + '_> = match (&self.rule, self.start) {
+                // `after` skips the time it is given
+                (Some(rule), _) => match Local.timestamp_opt(from as i64 - 1, 0).earliest() {
+                    Some(after) => Box::new(
+                        rule.after(&after)
+                            .map(|t| t.timestamp().try_into().unwrap_or(u32::MAX)),
+                    ),
+                    None => Box::new(std::iter::empty()),
+                },
+                (None, Some(start)) if start >= from => Box::new(std::iter::once(start)),
+                (None, _) => Box::new(std::iter::empty()),
+            };
+            Box::new(
+                times
+                    .take_while(move |t| *t <= end)
+                    .filter(move |t| !self.exceptions.contains(t)),
+            )
+        }
+
+        /// Every time it takes place within `[from, until)`
+        pub fn between(&self, from: u32, until: u32) -> Vec<u32> {
+            self.starting_at(from).take_while(|t| *t < until).collect()
+        }
+    }
+
+    /// One occurrence of an element
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct Occurrence {
+        /// When the event itself takes place
+        pub event: u32,
+        /// When preparing for it starts, the event time if there is no preparation
+        pub prepare: u32,
+        /// When it is announced, at the start of the preparation
+        pub alert: u32,
+    }
+
+    impl Occurrence {
+        /// The occurrence of an event at `event` that takes `preparation` seconds to prepare for
+        pub fn new(event: u32, preparation: u32) -> Self {
+            let prepare = event.saturating_sub(preparation);
+            Self {
+                event,
+                prepare,
+                alert: prepare,
+            }
+        }
+
+        /// The event time as `HH:MM` in local time
+        pub fn event_time(&self) -> Option<String> {
+            Local
+                .timestamp_opt(self.event as i64, 0)
+                .earliest()
+                .map(|t| t.format("%H:%M").to_string())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::time_source::local_time_zone;
+
+        const DAILY: &str = "0 0 7 * * * *";
+        const DAY: u32 = 24 * 60 * 60;
+        /// 2024-03-05 07:00 in Europe/Berlin
+        const TUESDAY: u32 = 1_709_618_400;
+
+        #[test]
+        fn single_event_at_start() {
+            let once = Recurrence::new(Some(TUESDAY), None, None, &[]);
+            assert_eq!(once.between(TUESDAY - DAY, TUESDAY + DAY), vec![TUESDAY]);
+            assert_eq!(once.between(TUESDAY + 1, TUESDAY + DAY), Vec::<u32>::new());
+            assert_eq!(Recurrence::new(None, None, None, &[]).between(0, u32::MAX), Vec::<u32>::new());
+        }
+
+        #[test]
+        fn rule_starts_at_start() {
+            let _tz = local_time_zone("Europe/Berlin");
+            let daily = Recurrence::new(Some(TUESDAY + DAY), Some(DAILY), None, &[]);
+            assert_eq!(
+                daily.between(TUESDAY - DAY, TUESDAY + 3 * DAY),
+                vec![TUESDAY + DAY, TUESDAY + 2 * DAY]
+            );
+        }
+
+        #[test]
+        fn rule_ends_at_end_inclusive() {
+            let _tz = local_time_zone("Europe/Berlin");
+            let daily = Recurrence::new(Some(TUESDAY), Some(DAILY), Some(TUESDAY + DAY), &[]);
+            assert_eq!(daily.between(TUESDAY, TUESDAY + 5 * DAY), vec![TUESDAY, TUESDAY + DAY]);
+        }
+
+        #[test]
+        fn exceptions_are_left_out() {
+            let _tz = local_time_zone("Europe/Berlin");
+            let daily = Recurrence::new(Some(TUESDAY), Some(DAILY), None, &[TUESDAY + DAY]);
+            assert_eq!(
+                daily.between(TUESDAY, TUESDAY + 3 * DAY),
+                vec![TUESDAY, TUESDAY + 2 * DAY]
+            );
+        }
+
+        #[test]
+        fn earlier_occurrence_today_is_included() {
+            let _tz = local_time_zone("Europe/Berlin");
+            // The day from midnight, asked for later in the afternoon
+            let midnight = TUESDAY - 7 * 60 * 60;
+            let daily = Recurrence::new(Some(TUESDAY - 7 * DAY), Some(DAILY), None, &[]);
+            assert_eq!(daily.between(midnight, midnight + DAY), vec![TUESDAY]);
+            assert_eq!(daily.starting_at(TUESDAY).next(), Some(TUESDAY));
+            assert_eq!(daily.starting_at(TUESDAY + 1).next(), Some(TUESDAY + DAY));
+        }
+
+        #[test]
+        fn invalid_rule_falls_back_to_start() {
+            let broken = Recurrence::new(Some(TUESDAY), Some("every morning"), None, &[]);
+            assert_eq!(broken.between(0, u32::MAX), vec![TUESDAY]);
+        }
+
+        #[test]
+        fn occurrence_alerts_at_preparation() {
+            let prepared = Occurrence::new(TUESDAY, 15 * 60);
+            assert_eq!(prepared.event, TUESDAY);
+            assert_eq!(prepared.prepare, TUESDAY - 15 * 60);
+            assert_eq!(prepared.alert, prepared.prepare);
+
+            let unprepared = Occurrence::new(TUESDAY, 0);
+            assert_eq!((unprepared.prepare, unprepared.alert), (TUESDAY, TUESDAY));
+            assert_eq!(Occurrence::new(60, 120).prepare, 0);
+        }
+
+        #[test]
+        fn event_time_in_local_time() {
+            let _tz = local_time_zone("Europe/Berlin");
+            assert_eq!(Occurrence::new(TUESDAY, 0).event_time().as_deref(), Some("07:00"));
+        }
+    }
+}
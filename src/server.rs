@@ -0,0 +1,361 @@
+pub mod media_server {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+    use ipnetwork::Ipv4Network;
+    use percent_encoding::percent_decode_str;
+    use serde::{Deserialize, Serialize};
+    use std::convert::Infallible;
+    use std::fmt;
+    use std::io::SeekFrom;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::Arc;
+    use tokio::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use tokio_util::io::ReaderStream;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct MediaServerConfig {
+        enabled: bool,
+        address: Option<Ipv4Addr>,
+        port: u16,
+    }
+
+    impl ::std::default::Default for MediaServerConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                address: None,
+                port: 8321,
+            }
+        }
+    }
+
+    impl fmt::Display for MediaServerConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", serde_json::to_string(self).unwrap())
+        }
+    }
+
+    impl MediaServerConfig {
+        pub fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        pub fn port(&self) -> u16 {
+            self.port
+        }
+
+        /// Picks the address to bind to. A configured address always wins, otherwise
+        /// the first local network that contains `target` (usually the speaker) is
+        /// used, falling back to the first available address.
+        pub fn select_address(&self, networks: &[Ipv4Network], target: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+            if self.address.is_some() {
+                return self.address;
+            }
+            if let Some(target) = target {
+                if let Some(net) = networks.iter().find(|n| n.contains(target)) {
+                    return Some(net.ip());
+                }
+            }
+            networks.first().map(|n| n.ip())
+        }
+    }
+
+    /// A small HTTP server exposing the audio files of the media directory to the
+    /// speakers. Supports `GET` and `HEAD` as well as single byte ranges so the
+    /// speaker can seek.
+    #[derive(Debug, Clone)]
+    pub struct MediaServer {
+        root: PathBuf,
+        addr: SocketAddr,
+    }
+
+    impl MediaServer {
+        pub fn new(root: PathBuf, address: Ipv4Addr, port: u16) -> Self {
+            Self {
+                root,
+                addr: SocketAddr::from((address, port)),
+            }
+        }
+
+        /// The base URL under which files of the media directory are reachable
+        pub fn base_url(&self) -> String {
+            format!("http://{}/", self.addr)
+        }
+
+        /// Binds the server and serves requests in a background task.
+        /// Binding errors are returned immediately instead of inside the task.
+        pub fn spawn(&self) -> Result<tokio::task::JoinHandle<()>, hyper::Error> {
+            let root = Arc::new(self.root.clone());
+            let make_svc = make_service_fn(move |_conn| {
+                let root = root.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| handle(root.clone(), req)))
+                }
+            });
+
+            let server = Server::try_bind(&self.addr)?.serve(make_svc);
+            log::info!("Serving {} on {}", self.root.display(), self.base_url());
+
+            Ok(tokio::spawn(async move {
+                if let Err(err) = server.await {
+                    log::error!("Media server stopped: {}", err);
+                }
+            }))
+        }
+    }
+
+    /// Returns the MIME type to announce for the given file
+    pub fn mime_type(path: &Path) -> &'static str {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "mp3" => "audio/mpeg",
+            "flac" => "audio/flac",
+            "wav" => "audio/wav",
+            "ogg" | "oga" => "audio/ogg",
+            "opus" => "audio/opus",
+            "m4a" | "mp4" => "audio/mp4",
+            "aac" => "audio/aac",
+            // What `say` on macOS writes
+            "caf" => "audio/x-caf",
+            "m3u" => "audio/x-mpegurl",
+            "pls" => "audio/x-scpls",
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "txt" => "text/plain",
+            "xml" => "text/xml",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Parses a `Range` header value for a file of length `len`.
+    /// Returns the inclusive byte range, or `Err(())` if the range can not be satisfied.
+    /// Multiple ranges are not supported and the first one is used.
+    pub fn parse_range(value: &str, len: u64) -> Result<(u64, u64), ()> {
+        let spec = value.trim().strip_prefix("bytes=").ok_or(())?;
+        let first = spec.split(',').next().ok_or(())?.trim();
+        let (start, end) = first.split_once('-').ok_or(())?;
+
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return Err(()),
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().map_err(|_| ())?;
+                if suffix == 0 {
+                    return Err(());
+                }
+                (len.saturating_sub(suffix), len.saturating_sub(1))
+            }
+            (start, "") => (start.parse().map_err(|_| ())?, len.saturating_sub(1)),
+            (start, end) => {
+                let end: u64 = end.parse().map_err(|_| ())?;
+                (start.parse().map_err(|_| ())?, end.min(len.saturating_sub(1)))
+            }
+        };
+
+        if len == 0 || start > end || start >= len {
+            return Err(());
+        }
+        Ok((start, end))
+    }
+
+    /// Maps the request path onto the media directory, rejecting anything that
+    /// would escape it and anything that is not audio, like the templates.
+    fn resolve(root: &Path, uri_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_str(uri_path).decode_utf8().ok()?;
+        let mut path = root.to_path_buf();
+        for component in Path::new(decoded.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        mime_type(&path).starts_with("audio/").then_some(path)
+    }
+
+    fn status(code: StatusCode) -> Response<Body> {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = code;
+        res
+    }
+
+    async fn handle(root: Arc<PathBuf>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        log::debug!("Media server: {} {}", req.method(), req.uri());
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+
+        let path = match resolve(&root, req.uri().path()) {
+            Some(val) if val.is_file() => val,
+            Some(_) => return Ok(status(StatusCode::NOT_FOUND)),
+            None => return Ok(status(StatusCode::FORBIDDEN)),
+        };
+
+        match serve_file(&path, &req).await {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                log::error!("Failed to serve {}: {}", path.display(), err);
+                Ok(status(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+
+    async fn serve_file(path: &Path, req: &Request<Body>) -> std::io::Result<Response<Body>> {
+        let mut file = File::open(path).await?;
+        let len = file.metadata().await?.len();
+
+        let range = match req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()) {
+            Some(val) => match parse_range(val, len) {
+                Ok(range) => Some(range),
+                Err(()) => {
+                    let mut res = status(StatusCode::RANGE_NOT_SATISFIABLE);
+                    res.headers_mut().insert(
+                        header::CONTENT_RANGE,
+                        header::HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+                    );
+                    return Ok(res);
+                }
+            },
+            None => None,
+        };
+
+        let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+        let length = if len == 0 { 0 } else { end - start + 1 };
+
+        let mut builder = Response::builder()
+            .header(header::CONTENT_TYPE, mime_type(path))
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::CONTENT_LENGTH, length);
+        if range.is_some() {
+            builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+        }
+
+        let body = if req.method() == Method::HEAD {
+            Body::empty()
+        } else {
+            file.seek(SeekFrom::Start(start)).await?;
+            Body::wrap_stream(ReaderStream::new(file.take(length)))
+        };
+
+        Ok(builder.body(body).unwrap())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parses_closed_ranges() {
+            assert_eq!(parse_range("bytes=0-99", 1000), Ok((0, 99)));
+            assert_eq!(parse_range("bytes=100-2000", 1000), Ok((100, 999)));
+            assert_eq!(parse_range("bytes=5-9, 20-30", 1000), Ok((5, 9)));
+        }
+
+        #[test]
+        fn parses_suffix_ranges() {
+            assert_eq!(parse_range("bytes=-100", 1000), Ok((900, 999)));
+            assert_eq!(parse_range("bytes=-5000", 1000), Ok((0, 999)));
+            assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        }
+
+        #[test]
+        fn parses_open_ended_ranges() {
+            assert_eq!(parse_range("bytes=500-", 1000), Ok((500, 999)));
+            assert_eq!(parse_range("bytes=999-", 1000), Ok((999, 999)));
+        }
+
+        #[test]
+        fn rejects_invalid_ranges() {
+            assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+            assert_eq!(parse_range("bytes=50-10", 1000), Err(()));
+            assert_eq!(parse_range("bytes=-", 1000), Err(()));
+            assert_eq!(parse_range("bytes=a-b", 1000), Err(()));
+            assert_eq!(parse_range("items=0-10", 1000), Err(()));
+            assert_eq!(parse_range("bytes=0-10", 0), Err(()));
+        }
+
+        #[test]
+        fn resolves_audio_inside_the_root() {
+            let root = Path::new("/srv/media");
+            assert_eq!(resolve(root, "/tts.mp3"), Some(root.join("tts.mp3")));
+            assert_eq!(resolve(root, "/music/./My%20Song.flac"), Some(root.join("music/My Song.flac")));
+        }
+
+        #[test]
+        fn serves_speech_of_command_engines() {
+            let root = Path::new("/srv/media");
+            assert_eq!(mime_type(Path::new("tts.caf")), "audio/x-caf");
+            assert_eq!(resolve(root, "/tts.caf"), Some(root.join("tts.caf")));
+            assert_eq!(mime_type(Path::new("tts.wav")), "audio/wav");
+        }
+
+        #[test]
+        fn rejects_traversal() {
+            let root = Path::new("/srv/media");
+            assert_eq!(resolve(root, "/../etc/passwd.mp3"), None);
+            assert_eq!(resolve(root, "/music/%2E%2E/%2E%2E/secret.mp3"), None);
+            assert_eq!(resolve(root, "/music/..%2F..%2Fsecret.mp3"), None);
+        }
+
+        #[tokio::test]
+        async fn serves_files_over_http() {
+            let root = std::env::temp_dir().join(format!("freemind-sonos-server-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            let audio: Vec<u8> = (0..100).collect();
+            std::fs::write(root.join("song.mp3"), &audio).unwrap();
+            std::fs::write(root.join("notes.txt"), "private").unwrap();
+
+            let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+            let server = MediaServer::new(root.clone(), Ipv4Addr::LOCALHOST, port);
+            let task = server.spawn().unwrap();
+            let url = format!("{}song.mp3", server.base_url());
+            let client = reqwest::Client::new();
+
+            let res = client.get(&url).header("Range", "bytes=10-19").send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+            assert_eq!(res.headers()["content-range"], "bytes 10-19/100");
+            assert_eq!(res.headers()["content-type"], "audio/mpeg");
+            assert_eq!(res.bytes().await.unwrap().as_ref(), &audio[10..20]);
+
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            assert_eq!(res.headers()["accept-ranges"], "bytes");
+            assert_eq!(res.bytes().await.unwrap().as_ref(), &audio[..]);
+
+            let res = client.head(&url).send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            assert_eq!(res.headers()["content-length"], "100");
+            assert!(res.bytes().await.unwrap().is_empty());
+
+            let res = client.get(&url).header("Range", "bytes=200-").send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(res.headers()["content-range"], "bytes */100");
+
+            let status = |path: &str| client.get(format!("{}{}", server.base_url(), path)).send();
+            assert_eq!(status("missing.mp3").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+            assert_eq!(status("notes.txt").await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+            let res = client.post(&url).send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+
+            task.abort();
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+
+        #[test]
+        fn rejects_everything_but_audio() {
+            let root = Path::new("/srv/media");
+            assert_eq!(resolve(root, "/registry.json"), None);
+            assert_eq!(resolve(root, "/announced.json"), None);
+            assert_eq!(resolve(root, "/templates/briefing.hbs"), None);
+            assert_eq!(resolve(root, "/"), None);
+        }
+    }
+}
@@ -1,17 +1,233 @@
 pub mod box_controller {
-    use serde::{Deserialize, Serialize};
-    use sonor::{args, rupnp::ssdp::URN, RepeatMode, Speaker};
-    use std::net::Ipv4Addr;
+    use serde::{Deserialize, Deserializer, Serialize};
+    use sonor::{args, rupnp, rupnp::ssdp::URN, RepeatMode, Speaker};
+    use std::net::{Ipv4Addr, SocketAddrV4};
     use std::fmt;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
 
     const AV_TRANSPORT: &URN = &URN::service("schemas-upnp-org", "AVTransport", 1);
+    #[allow(dead_code)]
     const DEVICE_PROPERTIES: &URN = &URN::service("schemas-upnp-org", "DeviceProperties", 1);
     const QUEUE: &URN = &URN::service("schemas-sonos-com", "Queue", 1);
+    #[allow(dead_code)]
     const ZONE_GROUP_TOPOLOGY: &URN = &URN::service("schemas-upnp-org", "ZoneGroupTopology", 1);
+    #[allow(dead_code)]
     const VIRTUAL_LINE_IN: &URN = &URN::service("schemas-upnp-org", "VirtualLineIn", 1);
 
+    /// How long to wait for speakers to answer a discovery request
+    const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+    /// Where discovery requests are sent, speakers answer from their own address
+    const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+    /// The device type Sonos speakers answer discovery requests for
+    const ZONE_PLAYER: &str = "urn:schemas-upnp-org:device:ZonePlayer:1";
 
-    #[derive(Serialize, Deserialize)]
+    /// Escapes text for XML. Action arguments are inserted into the SOAP body
+    /// as they are, so URIs and metadata have to be escaped first.
+    pub fn xml_escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    /// Returns the IPv4 address a connected speaker is reachable at
+    pub fn speaker_ip(spk: &Speaker) -> Option<Ipv4Addr> {
+        spk.device().url().host()?.parse().ok()
+    }
+
+    /// Where playback currently is, as reported by `GetPositionInfo`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Position {
+        pub track: u32,
+        pub uri: String,
+        /// Length of the track in seconds, 0 for streams
+        pub duration: u32,
+        pub elapsed: u32,
+    }
+
+    impl Position {
+        /// Seconds until the end of the track, `None` if the length is unknown
+        pub fn remaining(&self) -> Option<u32> {
+            if self.duration == 0 {
+                None
+            } else {
+                Some(self.duration.saturating_sub(self.elapsed))
+            }
+        }
+    }
+
+    /// How long playback may take to start before waiting for it to end is given up
+    const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Why waiting for the end of a track is over
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TrackEnd {
+        /// The track ends in this many seconds
+        Ending(u32),
+        /// Another track plays now
+        Changed,
+        /// Playback stopped after it had started
+        Stopped,
+        /// Playback never started
+        NotStarted,
+    }
+
+    /// Follows the events and polled positions of a speaker while waiting for
+    /// the current track to end. Streams have no length, so for them only
+    /// stopping or a change of the track count.
+    #[derive(Debug, Clone, Default)]
+    pub struct TrackWatch {
+        /// The speaker was polled while playing
+        started: bool,
+        /// An event reported playing, only a stop after that counts as older
+        /// events may still arrive
+        playing: bool,
+        /// Number and URI of the track playing when waiting started
+        current: Option<(u32, String)>,
+    }
+
+    impl TrackWatch {
+        /// Looks at an event with the new transport `state` and track `uri`, if they changed
+        pub fn event(&mut self, state: Option<&str>, uri: Option<&str>) -> Option<TrackEnd> {
+            if self.playing && matches!(state, Some("STOPPED") | Some("PAUSED_PLAYBACK")) {
+                return Some(TrackEnd::Stopped);
+            }
+            if let (Some(uri), Some((_, current))) = (uri, &self.current) {
+                if !uri.is_empty() && uri != current {
+                    return Some(TrackEnd::Changed);
+                }
+            }
+            self.playing |= state == Some("PLAYING");
+            None
+        }
+
+        /// Looks at the polled position, `playing` tells whether the speaker plays
+        /// and `waited` how long waiting has taken so far
+        pub fn observe(&mut self, pos: &Position, playing: bool, waited: Duration) -> Option<TrackEnd> {
+            if playing {
+                self.started = true;
+            } else if self.started {
+                return Some(TrackEnd::Stopped);
+            } else if waited > START_TIMEOUT {
+                return Some(TrackEnd::NotStarted);
+            }
+
+            match &self.current {
+                None => self.current = Some((pos.track, pos.uri.clone())),
+                Some((track, uri)) if *track != pos.track || *uri != pos.uri => return Some(TrackEnd::Changed),
+                _ => {}
+            }
+
+            pos.remaining().filter(|remaining| *remaining <= 1).map(TrackEnd::Ending)
+        }
+    }
+
+    /// Parses durations like `0:03:25`, anything unparsable (e.g. `NOT_IMPLEMENTED`) is 0
+    fn parse_hms(value: &str) -> u32 {
+        value
+            .split(':')
+            .try_fold(0u32, |acc, part| part.split('.').next()?.parse::<u32>().ok().map(|v| acc * 60 + v))
+            .unwrap_or(0)
+    }
+
+    pub async fn position(spk: &Speaker) -> Result<Position, sonor::Error> {
+        let mut info = spk
+            .action(AV_TRANSPORT, "GetPositionInfo", args! { "InstanceID": "0" })
+            .await?;
+        Ok(Position {
+            track: info.remove("Track").and_then(|t| t.parse().ok()).unwrap_or(0),
+            uri: info.remove("TrackURI").unwrap_or_default(),
+            duration: info.remove("TrackDuration").map(|d| parse_hms(&d)).unwrap_or(0),
+            elapsed: info.remove("RelTime").map(|d| parse_hms(&d)).unwrap_or(0),
+        })
+    }
+
+    /// The address of the speaker that sent `response` to a discovery request, taken
+    /// from its description URL. Answers of other devices are `None`.
+    pub fn parse_search_response(response: &str) -> Option<Ipv4Addr> {
+        let mut lines = response.lines();
+        if !lines.next()?.starts_with("HTTP/1.1 200") {
+            return None;
+        }
+        let mut target = None;
+        let mut location = None;
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "st" => target = Some(value.trim()),
+                "location" => location = Some(value.trim()),
+                _ => {}
+            }
+        }
+        if target? != ZONE_PLAYER {
+            return None;
+        }
+        url::Url::parse(location?).ok()?.host_str()?.parse().ok()
+    }
+
+    /// Sends a discovery request to `target` and returns the address of every
+    /// speaker answering within `timeout`
+    async fn search(target: SocketAddrV4, timeout: Duration) -> std::io::Result<Vec<Ipv4Addr>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+            SSDP_ADDR,
+            timeout.as_secs().max(1),
+            ZONE_PLAYER
+        );
+        socket.send_to(request.as_bytes(), target).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut found = Vec::new();
+        let mut buf = [0u8; 2048];
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, _) = received?;
+            match parse_search_response(&String::from_utf8_lossy(&buf[..len])) {
+                Some(ip) if !found.contains(&ip) => found.push(ip),
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    /// Connects to every speaker answering a discovery request
+    async fn discover() -> Result<Vec<Speaker>, sonor::Error> {
+        let found = search(SSDP_ADDR, DISCOVERY_TIMEOUT)
+            .await
+            .map_err(rupnp::Error::IO)?;
+        let mut speakers = Vec::new();
+        for ip in found {
+            match Speaker::from_ip(ip).await {
+                Ok(Some(spk)) => speakers.push(spk),
+                Ok(None) => log::debug!("{} answered the discovery but is not a Sonos speaker", ip),
+                Err(err) => log::warn!("Failed to connect to discovered speaker {}: {:?}", ip, err),
+            }
+        }
+        Ok(speakers)
+    }
+
+    /// Searches the network for speakers and returns their room names and addresses
+    pub async fn discover_speakers() -> Result<Vec<(String, Option<Ipv4Addr>)>, sonor::Error> {
+        let mut result = Vec::new();
+        for spk in discover().await? {
+            result.push((spk.name().await?, speaker_ip(&spk)));
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Discovers the speaker of the room named `room`, ignoring case
+    async fn find(room: &str) -> Result<Option<Speaker>, sonor::Error> {
+        for spk in discover().await? {
+            if spk.name().await?.eq_ignore_ascii_case(room) {
+                return Ok(Some(spk));
+            }
+        }
+        Ok(None)
+    }
+
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     struct SoundConfig {
         volume: u16,
         crossfade: bool,
@@ -40,15 +256,245 @@ pub mod box_controller {
         }
 }
 
-    #[derive(Serialize, Deserialize)]
+    /// Makes every member join the group of `coordinator`, so they all play the same.
+    /// Returns the members that joined successfully.
+    pub async fn group_speakers(coordinator: &Speaker, members: Vec<Speaker>) -> Vec<Speaker> {
+        if members.is_empty() {
+            return members;
+        }
+        let name = match coordinator.name().await {
+            Ok(name) => name,
+            Err(err) => {
+                log::error!("Failed to get the name of the group coordinator: {:?}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut grouped = Vec::new();
+        for member in members {
+            match member.join(&name).await {
+                Ok(true) => grouped.push(member),
+                Ok(false) => log::warn!("{} is not part of the zone group topology", name),
+                Err(err) => log::error!("Failed to join {}: {:?}", name, err),
+            }
+        }
+        log::info!("Grouped {} speakers with {}", grouped.len(), name);
+        grouped
+    }
+
+    /// Collects URIs with their DIDL-Lite metadata and plays them one after another
+    /// from the speaker's queue
+    #[derive(Debug, Clone, Default)]
+    pub struct QueueBuilder {
+        items: Vec<(String, String)>,
+    }
+
+    impl QueueBuilder {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn add(mut self, uri: &str, metadata: &str) -> Self {
+            self.items.push((uri.to_string(), metadata.to_string()));
+            self
+        }
+
+        pub fn len(&self) -> usize {
+            self.items.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.items.is_empty()
+        }
+
+        /// Replaces the queue of `spk` with the collected items and makes it play
+        /// the queue from the first one. Playback is not started.
+        pub async fn load(&self, spk: &Speaker) -> Result<(), sonor::Error> {
+            spk.clear_queue().await?;
+            for (uri, metadata) in self.items.iter() {
+                spk.queue_end(&xml_escape(uri), &xml_escape(metadata)).await?;
+            }
+            let queue = format!("x-rincon-queue:{}#0", spk.uuid().await?);
+            spk.set_transport_uri(&queue, "").await?;
+            spk.seek_track(1).await?;
+            log::debug!("Queued {} items", self.items.len());
+            Ok(())
+        }
+    }
+
+    /// Splits a DIDL-Lite document into one document per item, keeping the
+    /// namespaces declared by the original
+    fn split_didl(didl: &str) -> Vec<String> {
+        let start = match didl.find("<item") {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+        let header = &didl[..start];
+        didl[start..]
+            .split_inclusive("</item>")
+            .map(str::trim)
+            .filter(|item| item.starts_with("<item"))
+            .map(|item| format!("{}{}</DIDL-Lite>", header, item))
+            .collect()
+    }
+
+    /// The DIDL-Lite metadata of every queue entry, in queue order. `Speaker::queue`
+    /// only keeps a few fields, so the queue is browsed again for the full items.
+    async fn queue_metadata(spk: &Speaker) -> Vec<String> {
+        let args = args! { "QueueID": 0, "StartingIndex": 0, "RequestedCount": u32::MAX };
+        match spk.action(QUEUE, "Browse", args).await {
+            Ok(mut result) => result.remove("Result").map(|didl| split_didl(&didl)).unwrap_or_default(),
+            Err(err) => {
+                log::debug!("Failed to read the queue metadata: {:?}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Everything needed to put a speaker back into the state it was in before an
+    /// announcement: transport URI, queue, position, play state and sound settings.
+    /// Group membership is part of the transport URI (`x-rincon:<coordinator>`).
+    #[derive(Debug, Clone, Default)]
+    pub struct SpeakerSnapshot {
+        transport_uri: Option<String>,
+        metadata: String,
+        /// URI and DIDL-Lite metadata of every queue entry
+        queue: Vec<(String, String)>,
+        track: Option<(u32, u32)>,
+        is_playing: bool,
+        play_mode: Option<String>,
+        volume: Option<u16>,
+        crossfade: Option<bool>,
+        loudness: Option<bool>,
+        treble: Option<i8>,
+        bass: Option<i8>,
+    }
+
+    impl SpeakerSnapshot {
+        /// Captures the current state of `spk`. Anything that can't be read is
+        /// skipped and left untouched when restoring.
+        pub async fn take(spk: &Speaker) -> Self {
+            let mut snapshot = SpeakerSnapshot::default();
+            if let Ok(mut info) = spk.action(AV_TRANSPORT, "GetMediaInfo", args! { "InstanceID": "0" }).await {
+                snapshot.transport_uri = info.remove("CurrentURI").filter(|uri| !uri.is_empty());
+                snapshot.metadata = info.remove("CurrentURIMetaData").unwrap_or_default();
+            }
+            if let Ok(mut settings) = spk.action(AV_TRANSPORT, "GetTransportSettings", args! { "InstanceID": "0" }).await {
+                snapshot.play_mode = settings.remove("PlayMode");
+            }
+            let metadata = queue_metadata(spk).await;
+            snapshot.queue = spk
+                .queue()
+                .await
+                .map(|tracks| {
+                    tracks
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| (t.uri().to_string(), metadata.get(i).cloned().unwrap_or_default()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            snapshot.track = spk
+                .track()
+                .await
+                .ok()
+                .flatten()
+                .map(|t| (t.track_no(), t.elapsed()));
+            snapshot.is_playing = spk.is_playing().await.unwrap_or(false);
+            snapshot.volume = spk.volume().await.ok();
+            snapshot.crossfade = spk.crossfade().await.ok();
+            snapshot.loudness = spk.loudness().await.ok();
+            snapshot.treble = spk.treble().await.ok();
+            snapshot.bass = spk.bass().await.ok();
+            log::debug!("Took snapshot {:?}", snapshot);
+            snapshot
+        }
+
+        /// Puts `spk` back into the captured state
+        pub async fn restore(&self, spk: &Speaker) {
+            spk.stop().await.unwrap_or(());
+            if let Some(volume) = self.volume {
+                spk.set_volume(volume).await.unwrap_or_else(|_| log::debug!("Failed to restore volume"));
+            }
+            if let Some(crossfade) = self.crossfade {
+                spk.set_crossfade(crossfade).await.unwrap_or_else(|_| log::debug!("Failed to restore crossfade"));
+            }
+            if let Some(loudness) = self.loudness {
+                spk.set_loudness(loudness).await.unwrap_or_else(|_| log::debug!("Failed to restore loudness"));
+            }
+            if let Some(treble) = self.treble {
+                spk.set_treble(treble).await.unwrap_or_else(|_| log::debug!("Failed to restore treble"));
+            }
+            if let Some(bass) = self.bass {
+                spk.set_bass(bass).await.unwrap_or_else(|_| log::debug!("Failed to restore bass"));
+            }
+
+            spk.clear_queue().await.unwrap_or(());
+            for (uri, metadata) in self.queue.iter() {
+                spk.queue_end(&xml_escape(uri), &xml_escape(metadata))
+                    .await
+                    .unwrap_or_else(|_| log::debug!("Failed to restore queue entry {}", uri));
+            }
+
+            let uri = match &self.transport_uri {
+                Some(uri) if uri.starts_with("x-sonos-vli") => {
+                    log::warn!("Can't restore line-in or app controlled playback");
+                    return;
+                }
+                Some(uri) => uri,
+                None => {
+                    spk.leave().await.unwrap_or(());
+                    return;
+                }
+            };
+            if spk.set_transport_uri(&xml_escape(uri), &xml_escape(&self.metadata)).await.is_err() {
+                log::error!("Failed to restore transport URI {}", uri);
+                return;
+            }
+            if let Some(mode) = &self.play_mode {
+                if spk.action(AV_TRANSPORT, "SetPlayMode", args! { "InstanceID": "0", "NewPlayMode": mode.as_str() }).await.is_err() {
+                    log::debug!("Failed to restore play mode");
+                }
+            }
+            if let Some((track_no, elapsed)) = self.track {
+                if uri.starts_with("x-rincon-queue:") {
+                    spk.seek_track(track_no).await.unwrap_or(());
+                }
+                spk.skip_to(elapsed).await.unwrap_or(());
+            }
+            if self.is_playing && !uri.starts_with("x-rincon:") {
+                spk.play().await.unwrap_or_else(|_| log::debug!("Failed to resume playback"));
+            }
+        }
+    }
+
+    /// Accepts either a single `[speaker]` table or a list of `[[speakers]]`
+    pub fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SpeakerBox>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(SpeakerBox),
+            Many(Vec<SpeakerBox>),
+        }
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(speaker) => vec![speaker],
+            OneOrMany::Many(speakers) => speakers,
+        })
+    }
+
+    /// A speaker is found by its room name, a configured IP is tried first and
+    /// discovery is used if that fails or no IP is set.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct SpeakerBox {
-        ip: Ipv4Addr,
+        room: Option<String>,
+        ip: Option<Ipv4Addr>,
         sound: SoundConfig,
     }
     impl ::std::default::Default for SpeakerBox {
         fn default() -> Self {
             Self {
-                ip: Ipv4Addr::new(127, 0, 0, 1),
+                room: Some("Bedroom".to_string()),
+                ip: None,
                 sound: SoundConfig::default(),
             }
         }
@@ -59,85 +505,257 @@ pub mod box_controller {
         }
     }
     impl SpeakerBox {
-        pub fn ip(&self) -> Ipv4Addr {
-            return self.ip;
+        pub fn ip(&self) -> Option<Ipv4Addr> {
+            self.ip
+        }
+
+        /// Name used in log messages, the room if known or the IP otherwise
+        pub fn label(&self) -> String {
+            match (&self.room, self.ip) {
+                (Some(room), _) => room.clone(),
+                (None, Some(ip)) => ip.to_string(),
+                (None, None) => "unconfigured speaker".to_string(),
+            }
         }
 
-        pub async fn to_speaker(&self) -> Option<Speaker> {
-            if let Some(spk) = match Speaker::from_ip(self.ip).await {
-                Ok(val) => val,
-                Err(err) => {
-                    log::error!("{:?}", err);
-                    None
+        /// Connects to the speaker, first by IP and then by discovering the room
+        pub async fn connect(&self) -> Option<Speaker> {
+            if let Some(ip) = self.ip {
+                match Speaker::from_ip(ip).await {
+                    Ok(Some(spk)) => return Some(spk),
+                    Ok(None) => log::warn!("{} is not a Sonos speaker", ip),
+                    Err(err) => log::warn!("Failed to connect to {}: {:?}", ip, err),
                 }
-            } {
-                spk.stop()
-                    .await
-                    .unwrap_or(log::debug!("Failed to stop playback for {}", self.ip));
-                /*
-                spk.action(
-                    DEVICE_PROPERTIES,
-                    "RoomDetectionStartChirping",
-                    args! {"Channel": "10", "DurationMilliseconds": "500"},
-                )
+            }
+            if let Some(room) = &self.room {
+                log::debug!("Searching for {} . . .", room);
+                match find(room).await {
+                    Ok(Some(spk)) => {
+                        log::info!("Found {} at {:?}", room, speaker_ip(&spk));
+                        return Some(spk);
+                    }
+                    Ok(None) => log::warn!("No speaker named {} found", room),
+                    Err(err) => log::error!("Discovery of {} failed: {:?}", room, err),
+                }
+            }
+            None
+        }
+
+        /// Stops playback, applies the configured sound settings and removes the
+        /// speaker from its group so it is ready for an announcement
+        pub async fn prepare(&self, spk: &Speaker) {
+            spk.stop()
                 .await
-                .unwrap();
-                */
-                spk.set_volume(self.sound.volume)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set volume for {}", self.ip));
-                spk.set_crossfade(self.sound.crossfade)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set crossfade for {}", self.ip));
-                spk.set_shuffle(self.sound.shuffle)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set shuffle for {}", self.ip));
-                spk.set_repeat_mode(if self.sound.repeat {
-                    RepeatMode::All
-                } else {
-                    RepeatMode::None
-                })
+                .unwrap_or_else(|_| log::debug!("Failed to stop playback for {}", self.label()));
+            /*
+            spk.action(
+                DEVICE_PROPERTIES,
+                "RoomDetectionStartChirping",
+                args! {"Channel": "10", "DurationMilliseconds": "500"},
+            )
+            .await
+            .unwrap();
+            */
+            spk.set_volume(self.sound.volume)
                 .await
-                .unwrap_or(log::debug!("Failed to set repeat mode for {}", self.ip));
-                spk.set_loudness(self.sound.loudness)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set loudness for {}", self.ip));
-                spk.set_treble(self.sound.treble)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set treble for {}", self.ip));
-                spk.set_bass(self.sound.bass)
-                    .await
-                    .unwrap_or(log::debug!("Failed to set bass for {}", self.ip));
-                spk.clear_queue()
-                    .await
-                    .unwrap_or(log::debug!("Failed to clear playlist for {}", self.ip));
-
-                if let Ok(response) = spk
-                    .action(
-                        AV_TRANSPORT,
-                        "BecomeCoordinatorOfStandaloneGroup",
-                        args! { "InstanceID": "0" },
-                    )
-                    .await
-                {
-                    log::info!("{:?}", response);
-                } else {
-                    log::error!("Failed to set Coordinator for {}", self.ip);
-                }
-                /*
-                spk.action(
-                    QUEUE,
-                    "CreateQueue",
-                    args! { "QueueOwnerID": "RINCON_949F3E77CCD201400", "QueueOwnerContext": "THIS_QUEUE", "QueuePolicy": "0"},
+                .unwrap_or_else(|_| log::debug!("Failed to set volume for {}", self.label()));
+            spk.set_crossfade(self.sound.crossfade)
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to set crossfade for {}", self.label()));
+            spk.set_shuffle(self.sound.shuffle)
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to set shuffle for {}", self.label()));
+            spk.set_repeat_mode(if self.sound.repeat {
+                RepeatMode::All
+            } else {
+                RepeatMode::None
+            })
+            .await
+            .unwrap_or_else(|_| log::debug!("Failed to set repeat mode for {}", self.label()));
+            spk.set_loudness(self.sound.loudness)
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to set loudness for {}", self.label()));
+            spk.set_treble(self.sound.treble)
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to set treble for {}", self.label()));
+            spk.set_bass(self.sound.bass)
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to set bass for {}", self.label()));
+            spk.clear_queue()
+                .await
+                .unwrap_or_else(|_| log::debug!("Failed to clear playlist for {}", self.label()));
+
+            if let Ok(response) = spk
+                .action(
+                    AV_TRANSPORT,
+                    "BecomeCoordinatorOfStandaloneGroup",
+                    args! { "InstanceID": "0" },
                 )
                 .await
-                .unwrap();
-                */
-                Some(spk)
+            {
+                log::info!("{:?}", response);
             } else {
-                log::warn!("Failed to connect to {}", self.ip);
-                None
+                log::error!("Failed to set Coordinator for {}", self.label());
             }
         }
     }
-}
\ No newline at end of file
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SONOS_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age = 1800\r\n\
+            EXT:\r\n\
+            LOCATION: http://192.168.1.20:1400/xml/device_description.xml\r\n\
+            SERVER: Linux UPnP/1.0 Sonos/70.3-35220 (ZPS12)\r\n\
+            ST: urn:schemas-upnp-org:device:ZonePlayer:1\r\n\
+            USN: uuid:RINCON_000E58A0123401400::urn:schemas-upnp-org:device:ZonePlayer:1\r\n\r\n";
+
+        const ROUTER_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+            LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+
+        #[test]
+        fn parses_durations() {
+            assert_eq!(parse_hms("0:03:25"), 205);
+            assert_eq!(parse_hms("1:02:03"), 3723);
+            assert_eq!(parse_hms("0:00:00"), 0);
+            assert_eq!(parse_hms("12:00:00"), 43200);
+        }
+
+        #[test]
+        fn ignores_fractions_of_seconds() {
+            assert_eq!(parse_hms("0:03:25.000"), 205);
+            assert_eq!(parse_hms("1:02:03.999"), 3723);
+        }
+
+        #[test]
+        fn unparsable_durations_are_zero() {
+            assert_eq!(parse_hms("NOT_IMPLEMENTED"), 0);
+            assert_eq!(parse_hms(""), 0);
+            assert_eq!(parse_hms("0:xx:10"), 0);
+            assert_eq!(parse_hms("-1:00:00"), 0);
+        }
+
+        fn at(track: u32, uri: &str, duration: u32, elapsed: u32) -> Position {
+            Position { track, uri: uri.to_string(), duration, elapsed }
+        }
+
+        /// Feeds `positions` polled every second while playing until waiting is over
+        fn watch(positions: &[Position]) -> Option<(usize, TrackEnd)> {
+            let mut watch = TrackWatch::default();
+            positions
+                .iter()
+                .enumerate()
+                .find_map(|(i, pos)| watch.observe(pos, true, Duration::from_secs(i as u64)).map(|end| (i, end)))
+        }
+
+        #[test]
+        fn waits_for_the_end_of_the_track() {
+            let positions: Vec<Position> = (180..=184).map(|s| at(1, "http://a/1.mp3", 185, s)).collect();
+            assert_eq!(watch(&positions), Some((4, TrackEnd::Ending(1))));
+            // Jumping right to the end still ends it
+            assert_eq!(watch(&[at(1, "http://a/1.mp3", 185, 100), at(1, "http://a/1.mp3", 185, 185)]), Some((1, TrackEnd::Ending(0))));
+        }
+
+        #[test]
+        fn notices_other_tracks() {
+            let positions = [at(1, "http://a/1.mp3", 185, 30), at(1, "http://a/1.mp3", 185, 31), at(2, "http://a/2.mp3", 200, 0)];
+            assert_eq!(watch(&positions), Some((2, TrackEnd::Changed)));
+            // A stream changes its URI rather than ending
+            let positions = [at(1, "x-rincon-mp3radio://a", 0, 5), at(1, "x-rincon-mp3radio://a", 0, 600), at(1, "http://b/tts.mp3", 0, 0)];
+            assert_eq!(watch(&positions), Some((2, TrackEnd::Changed)));
+        }
+
+        #[test]
+        fn streams_play_until_stopped() {
+            let stream = at(1, "x-rincon-mp3radio://a", 0, 0);
+            assert_eq!(watch(&vec![stream.clone(); 50]), None);
+
+            let mut watch = TrackWatch::default();
+            assert_eq!(watch.observe(&stream, true, Duration::from_secs(0)), None);
+            assert_eq!(watch.observe(&stream, false, Duration::from_secs(1)), Some(TrackEnd::Stopped));
+        }
+
+        #[test]
+        fn gives_up_when_playback_does_not_start() {
+            let pos = at(1, "http://a/tts.mp3", 20, 0);
+            let mut watch = TrackWatch::default();
+            assert_eq!(watch.observe(&pos, false, Duration::from_secs(5)), None);
+            assert_eq!(watch.observe(&pos, false, Duration::from_secs(11)), Some(TrackEnd::NotStarted));
+        }
+
+        #[test]
+        fn follows_events() {
+            let mut watch = TrackWatch::default();
+            // A stop sent before playback started is an old event
+            assert_eq!(watch.event(Some("STOPPED"), None), None);
+            assert_eq!(watch.event(Some("TRANSITIONING"), Some("http://a/tts.mp3")), None);
+            assert_eq!(watch.observe(&at(1, "http://a/tts.mp3", 20, 0), true, Duration::ZERO), None);
+            assert_eq!(watch.event(Some("PLAYING"), Some("http://a/tts.mp3")), None);
+            assert_eq!(watch.event(None, Some("")), None);
+            assert_eq!(watch.clone().event(None, Some("http://a/song.mp3")), Some(TrackEnd::Changed));
+            assert_eq!(watch.event(Some("PAUSED_PLAYBACK"), None), Some(TrackEnd::Stopped));
+        }
+
+        #[test]
+        fn splits_didl_items() {
+            let didl = "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\">\
+                <item id=\"Q:0/1\"><dc:title>One</dc:title><res>http://a/1.mp3</res></item>\
+                <item id=\"Q:0/2\"><dc:title>Two &amp; more</dc:title></item></DIDL-Lite>";
+            assert_eq!(
+                split_didl(didl),
+                vec![
+                    "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\">\
+                     <item id=\"Q:0/1\"><dc:title>One</dc:title><res>http://a/1.mp3</res></item></DIDL-Lite>",
+                    "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\">\
+                     <item id=\"Q:0/2\"><dc:title>Two &amp; more</dc:title></item></DIDL-Lite>",
+                ]
+            );
+            assert!(split_didl("<DIDL-Lite></DIDL-Lite>").is_empty());
+        }
+
+        #[test]
+        fn parses_speaker_responses() {
+            assert_eq!(parse_search_response(SONOS_RESPONSE), Some(Ipv4Addr::new(192, 168, 1, 20)));
+            let lowercase = "HTTP/1.1 200 OK\r\nst: urn:schemas-upnp-org:device:ZonePlayer:1\r\n\
+                location: http://10.0.0.7:1400/xml/device_description.xml\r\n\r\n";
+            assert_eq!(parse_search_response(lowercase), Some(Ipv4Addr::new(10, 0, 0, 7)));
+        }
+
+        #[test]
+        fn ignores_other_responses() {
+            assert_eq!(parse_search_response(ROUTER_RESPONSE), None);
+            assert_eq!(parse_search_response(&SONOS_RESPONSE.replace("200 OK", "404 Not Found")), None);
+            assert_eq!(parse_search_response("HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:ZonePlayer:1\r\n\r\n"), None);
+            assert_eq!(parse_search_response("NOTIFY * HTTP/1.1\r\n\r\n"), None);
+            assert_eq!(parse_search_response(""), None);
+        }
+
+        #[tokio::test]
+        async fn searches_a_fake_responder() {
+            let responder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = match responder.local_addr().unwrap() {
+                std::net::SocketAddr::V4(addr) => addr,
+                other => panic!("Bound to {}", other),
+            };
+            let fake = tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let (len, from) = responder.recv_from(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                for response in [SONOS_RESPONSE, ROUTER_RESPONSE, SONOS_RESPONSE] {
+                    responder.send_to(response.as_bytes(), from).await.unwrap();
+                }
+                request
+            });
+
+            let found = search(addr, Duration::from_millis(300)).await.unwrap();
+            let request = fake.await.unwrap();
+            assert!(request.starts_with("M-SEARCH * HTTP/1.1\r\n"));
+            assert!(request.contains("MAN: \"ssdp:discover\"\r\n"));
+            assert!(request.contains("ST: urn:schemas-upnp-org:device:ZonePlayer:1\r\n"));
+            assert_eq!(found, vec![Ipv4Addr::new(192, 168, 1, 20)]);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+pub mod json_store {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fs::{self, OpenOptions};
+    use std::io;
+    use std::path::PathBuf;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant, SystemTime};
+
+    /// How long to wait for another run to release the lock
+    const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The lock only guards a single read and write, an older one was left
+    /// behind by a run that crashed
+    const STALE_LOCK: Duration = Duration::from_secs(30);
+
+    /// Something kept in a `JsonStore`, made of records that each refer to a
+    /// point in time
+    pub trait Records: Default + Serialize + DeserializeOwned {
+        /// Records older than this many seconds are dropped when saving
+        const RETENTION: u32;
+        /// What the records are called in log messages
+        const NAME: &'static str;
+
+        /// Drops every record from before `oldest`
+        fn expire(&mut self, oldest: u32);
+    }
+
+    /// Records that outlive a single run, kept in a JSON file. Several runs may
+    /// share the file, so changes are made to what is on disk while holding a
+    /// lock file next to it.
+    #[derive(Debug, Clone, Default)]
+    pub struct JsonStore<R> {
+        path: PathBuf,
+        records: R,
+    }
+
+    /// Removes the lock file when dropped
+    struct Lock(PathBuf);
+
+    impl Drop for Lock {
+        fn drop(&mut self) {
+            fs::remove_file(&self.0).unwrap_or(());
+        }
+    }
+
+    impl<R: Records> JsonStore<R> {
+        /// Loads the records from `path`, starting empty if it does not exist or can't be read
+        pub fn load(path: PathBuf) -> Self {
+            let mut store = Self { path, records: R::default() };
+            store.reload();
+            store
+        }
+
+        /// Replaces the records with what is on disk now
+        pub fn reload(&mut self) {
+            self.records = match fs::read_to_string(&self.path) {
+                Ok(txt) => serde_json::from_str(&txt).unwrap_or_else(|err| {
+                    log::warn!("Ignoring unreadable {} {}: {}", R::NAME, self.path.display(), err);
+                    R::default()
+                }),
+                Err(_) => R::default(),
+            };
+        }
+
+        pub fn records(&self) -> &R {
+            &self.records
+        }
+
+        pub fn records_mut(&mut self) -> &mut R {
+            &mut self.records
+        }
+
+        /// Applies `change` to the records on disk, drops expired records and
+        /// writes them back. Holds the lock the whole time, so runs at the same
+        /// time don't lose each other's records.
+        pub fn update<T>(&mut self, now: u32, change: impl FnOnce(&mut R) -> T) -> io::Result<T> {
+            let _lock = self.lock()?;
+            self.reload();
+            let result = change(&mut self.records);
+            self.records.expire(now.saturating_sub(R::RETENTION));
+            self.write()?;
+            Ok(result)
+        }
+
+        /// `path` with `suffix` appended to the file name
+        fn sibling(&self, suffix: &str) -> PathBuf {
+            let mut name = self.path.clone().into_os_string();
+            name.push(suffix);
+            PathBuf::from(name)
+        }
+
+        fn lock(&self) -> io::Result<Lock> {
+            let path = self.sibling(".lock");
+            let start = Instant::now();
+            loop {
+                match OpenOptions::new().write(true).create_new(true).open(&path) {
+                    Ok(_) => return Ok(Lock(path)),
+                    Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+                    Err(_) => {}
+                }
+                let age = fs::metadata(&path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+                if age.is_some_and(|age| age > STALE_LOCK) {
+                    log::warn!("Removing the stale lock {}", path.display());
+                    fs::remove_file(&path).unwrap_or(());
+                } else if start.elapsed() > LOCK_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} is locked", self.path.display())));
+                } else {
+                    sleep(Duration::from_millis(20));
+                }
+            }
+        }
+
+        /// Writes the records to a temporary file first, so readers never see
+        /// half of them
+        fn write(&self) -> io::Result<()> {
+            let tmp = self.sibling(".tmp");
+            fs::write(&tmp, serde_json::to_string(&self.records).unwrap())?;
+            fs::rename(&tmp, &self.path)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde::Deserialize;
+        use std::collections::BTreeSet;
+
+        #[derive(Debug, Default, Serialize, Deserialize)]
+        struct Times(BTreeSet<u32>);
+
+        impl Records for Times {
+            const RETENTION: u32 = 100;
+            const NAME: &'static str = "test record";
+
+            fn expire(&mut self, oldest: u32) {
+                self.0.retain(|at| *at >= oldest);
+            }
+        }
+
+        #[test]
+        fn keeps_the_records_of_every_run() {
+            let dir = std::env::temp_dir().join(format!("freemind-sonos-store-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("times.json");
+            let mut first: JsonStore<Times> = JsonStore::load(path.clone());
+            let mut second: JsonStore<Times> = JsonStore::load(path.clone());
+
+            first.update(1000, |t| t.0.insert(950)).unwrap();
+            second.update(1000, |t| t.0.insert(980)).unwrap();
+            first.update(1060, |t| t.0.insert(1050)).unwrap();
+            let on_disk: JsonStore<Times> = JsonStore::load(path);
+            let mut files: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            files.sort();
+            fs::remove_dir_all(&dir).unwrap();
+
+            // 950 expired with the last update
+            assert_eq!(first.records().0, BTreeSet::from([980, 1050]));
+            assert_eq!(on_disk.records().0, BTreeSet::from([980, 1050]));
+            assert_eq!(second.records().0, BTreeSet::from([950, 980]));
+            assert_eq!(files, vec!["times.json"]);
+        }
+    }
+}